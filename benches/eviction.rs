@@ -0,0 +1,50 @@
+use cache_rs::{ConcurrentCache, LFUCache, NaiveLFUCache};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+const CAPACITIES: [usize; 3] = [1_000, 10_000, 100_000];
+
+// Every insert into a full cache has to find an eviction victim, so the time
+// per insert should not grow with the capacity.
+fn insert_into_full_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert_into_full_cache");
+
+    for &capacity in CAPACITIES.iter() {
+        let cache = NaiveLFUCache::new(capacity);
+        for i in 0..capacity {
+            cache.insert(i, i);
+        }
+        group.bench_with_input(
+            BenchmarkId::new("NaiveLFUCache", capacity),
+            &capacity,
+            |b, &capacity| {
+                let mut key = capacity;
+                b.iter(|| {
+                    cache.insert(key, key);
+                    key += 1;
+                })
+            },
+        );
+
+        let cache = LFUCache::new(capacity);
+        for i in 0..capacity {
+            cache.insert(i, i);
+        }
+        cache.sync();
+        group.bench_with_input(
+            BenchmarkId::new("LFUCache", capacity),
+            &capacity,
+            |b, &capacity| {
+                let mut key = capacity;
+                b.iter(|| {
+                    cache.insert(key, key);
+                    key += 1;
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, insert_into_full_cache);
+criterion_main!(benches);
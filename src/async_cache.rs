@@ -0,0 +1,275 @@
+use crate::builder::LFUCacheBuilder;
+use crate::lfu::{Backpressure, LFUCache, WriteOp};
+use crate::maintenance::MaintenanceTask;
+use crate::stats::CacheStats;
+use crate::value_initializer::AsyncValueInitializer;
+use crate::ConcurrentCache;
+use crossbeam_channel::TrySendError;
+use std::collections::hash_map::RandomState;
+use std::convert::Infallible;
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// The async companion of `LFUCache`.
+///
+/// `get_or_insert_with` takes a `Future`, and concurrent loads of the same key
+/// are shared across tasks. None of the methods block the executor thread:
+/// when the write op channel is full, the task applies the pending writes
+/// itself or yields to the executor.
+///
+/// It does not depend on a particular runtime.
+pub struct AsyncLFUCache<K, V, S = RandomState> {
+    cache: LFUCache<K, V, S>,
+    value_initializer: Arc<AsyncValueInitializer<K, V>>,
+}
+
+impl<K, V, S> Clone for AsyncLFUCache<K, V, S> {
+    fn clone(&self) -> Self {
+        Self {
+            cache: self.cache.clone(),
+            value_initializer: Arc::clone(&self.value_initializer),
+        }
+    }
+}
+
+impl<K, V> AsyncLFUCache<K, V, RandomState>
+where
+    K: Eq + Hash + Debug,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self::from_cache(LFUCache::new(capacity))
+    }
+
+    /// Returns an `LFUCacheBuilder`. Build the cache with `build_async`.
+    pub fn builder(capacity: usize) -> LFUCacheBuilder<K, V, RandomState> {
+        LFUCacheBuilder::new(capacity)
+    }
+}
+
+impl<K, V, S> AsyncLFUCache<K, V, S>
+where
+    K: Eq + Hash + Debug,
+    S: BuildHasher,
+{
+    pub fn new_with_hasher(capacity: usize, build_hasher: S) -> Self {
+        Self::from_cache(LFUCache::new_with_hasher(capacity, build_hasher))
+    }
+
+    pub(crate) fn from_cache(cache: LFUCache<K, V, S>) -> Self {
+        Self {
+            cache,
+            value_initializer: Arc::new(AsyncValueInitializer::new()),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<V>> {
+        self.cache.get(key)
+    }
+
+    pub async fn get_or_insert_with<F>(&self, key: K, init: F) -> Arc<V>
+    where
+        F: Future<Output = V>,
+    {
+        let init = async { Ok::<_, Infallible>(init.await) };
+        match self.try_get_or_insert_with(key, init).await {
+            Ok(v) => v,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Like `get_or_insert_with`, but `init` may fail. The error is not
+    /// cached, and concurrent callers for the same key get a clone of it.
+    pub async fn try_get_or_insert_with<F, E>(&self, key: K, init: F) -> Result<Arc<V>, E>
+    where
+        F: Future<Output = Result<V, E>>,
+        E: Clone + Send + Sync + 'static,
+    {
+        if let Some(v) = self.cache.get(&key) {
+            return Ok(v);
+        }
+
+        let key = Arc::new(key);
+        let mut op = None;
+        let result = self
+            .value_initializer
+            .try_init_or_read(
+                &key,
+                || self.cache.get_value(&key),
+                init,
+                |value| {
+                    let (v, o) = self.cache.insert_entry(Arc::clone(&key), value);
+                    op = o;
+                    v
+                },
+            )
+            .await;
+        if let Some(op) = op {
+            self.schedule_write_op(op).await;
+        }
+        result
+    }
+
+    pub async fn insert(&self, key: K, value: V) {
+        let op = self.cache.insert_op(key, value, None);
+        self.schedule_write_op(op).await;
+    }
+
+    /// Like `insert`, but the entry expires once `ttl` has passed, whatever
+    /// the cache-wide time-to-live is.
+    pub async fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        let op = self.cache.insert_op(key, value, Some(ttl));
+        self.schedule_write_op(op).await;
+    }
+
+    pub async fn remove(&self, key: &K) -> Option<Arc<V>> {
+        let (value, op) = self.cache.remove_entry(key);
+        if let Some(op) = op {
+            self.schedule_write_op(op).await;
+        }
+        value
+    }
+
+    pub fn sync(&self) {
+        self.cache.sync();
+    }
+
+    /// A snapshot of the statistics. They are only recorded if the cache was
+    /// built with `record_stats`.
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// See `LFUCache::window_capacity`.
+    pub fn window_capacity(&self) -> u64 {
+        self.cache.window_capacity()
+    }
+
+    pub(crate) fn maintenance_task(&self) -> MaintenanceTask
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        self.cache.maintenance_task()
+    }
+
+    // A full write buffer is handled like `Backpressure::DrainInline`, unless
+    // the cache drops writes. Waiting would hold up the executor's thread.
+    async fn schedule_write_op(&self, mut op: WriteOp<K, V>) {
+        loop {
+            match self.cache.try_schedule_write_op(op) {
+                Ok(()) => return,
+                Err(TrySendError::Full(returned))
+                    if self.cache.backpressure() == Backpressure::Drop =>
+                {
+                    return self.cache.drop_write_op(returned);
+                }
+                Err(TrySendError::Full(returned)) => {
+                    op = returned;
+                    // Make room by applying the pending writes, or give the
+                    // thread which is applying them a chance to finish.
+                    if !self.cache.try_apply_writes() {
+                        yield_now().await;
+                    }
+                }
+                Err(TrySendError::Disconnected(_)) => panic!("Failed to schedule a write op"),
+            }
+        }
+    }
+}
+
+fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncLFUCache;
+    use futures::executor::block_on;
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn basics() {
+        block_on(async {
+            let cache = AsyncLFUCache::new(3);
+            cache.insert("a", "alice").await;
+            cache.sync();
+            assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
+
+            let v = cache.get_or_insert_with("b", async { "bob" }).await;
+            assert_eq!(v, Arc::new("bob"));
+            let v = cache.get_or_insert_with("b", async { "bill" }).await;
+            assert_eq!(v, Arc::new("bob"));
+
+            let v = cache
+                .try_get_or_insert_with("c", async { Err("Failed to load") })
+                .await;
+            assert_eq!(v, Err("Failed to load"));
+            assert_eq!(cache.get(&"c"), None);
+        });
+    }
+
+    #[test]
+    fn inserts_do_not_block_on_a_full_channel() {
+        block_on(async {
+            let cache = AsyncLFUCache::new(10);
+            // Far more than the write op channel can hold.
+            for i in 0..10_000 {
+                cache.insert(i, i).await;
+            }
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn shared_loads() {
+        let cache = AsyncLFUCache::new(100);
+        let num_inits = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..16).map(|i| {
+            let (cache, num_inits) = (cache.clone(), Arc::clone(&num_inits));
+            tokio::spawn(async move {
+                cache
+                    .get_or_insert_with("key", async move {
+                        num_inits.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        i
+                    })
+                    .await
+            })
+        });
+        let values = join_all(tasks)
+            .await
+            .into_iter()
+            .map(|v| v.expect("Failed to join"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(num_inits.load(Ordering::SeqCst), 1);
+        assert!(values.iter().all(|v| Arc::ptr_eq(v, &values[0])));
+    }
+}
@@ -0,0 +1,307 @@
+use crate::clock::{Clock, SystemClock};
+use crate::expiration::Expiration;
+use crate::notification::{Notifier, RemovalCause};
+use crate::{AsyncLFUCache, Backpressure, Cache, LFUCache, NaiveLFUCache};
+use std::collections::hash_map::RandomState;
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Gives the weight of an entry, which counts against the cache's
+/// `max_weight`.
+pub(crate) type Weigher<K, V> = Arc<dyn Fn(&K, &V) -> u32 + Send + Sync>;
+
+/// Builds an `LFUCache` or an `AsyncLFUCache`.
+///
+/// ```
+/// use cache_rs::LFUCache;
+/// use std::time::Duration;
+///
+/// let cache: LFUCache<u32, String, _> = LFUCache::builder(1_000)
+///     .time_to_live(Duration::from_secs(30 * 60))
+///     .time_to_idle(Duration::from_secs(5 * 60))
+///     .build();
+/// ```
+pub struct LFUCacheBuilder<K, V, S = RandomState> {
+    pub(crate) capacity: usize,
+    pub(crate) max_weight: Option<u64>,
+    pub(crate) weigher: Option<Weigher<K, V>>,
+    pub(crate) build_hasher: S,
+    pub(crate) expiration: Expiration,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) notifier: Option<Notifier<K, V>>,
+    pub(crate) record_stats: bool,
+    pub(crate) backpressure: Backpressure,
+    pub(crate) doorkeeper: bool,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> LFUCacheBuilder<K, V, RandomState> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            max_weight: None,
+            weigher: None,
+            build_hasher: RandomState::default(),
+            expiration: Expiration::default(),
+            clock: Arc::new(SystemClock),
+            notifier: None,
+            record_stats: false,
+            backpressure: Backpressure::default(),
+            doorkeeper: false,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, S> LFUCacheBuilder<K, V, S> {
+    pub fn hasher<S2>(self, build_hasher: S2) -> LFUCacheBuilder<K, V, S2> {
+        LFUCacheBuilder {
+            capacity: self.capacity,
+            max_weight: self.max_weight,
+            weigher: self.weigher,
+            build_hasher,
+            expiration: self.expiration,
+            clock: self.clock,
+            notifier: self.notifier,
+            record_stats: self.record_stats,
+            backpressure: self.backpressure,
+            doorkeeper: self.doorkeeper,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Bounds the cache by the total weight of its entries rather than by
+    /// their number. The capacity is still used to size the cache.
+    pub fn max_weight(mut self, max_weight: u64) -> Self {
+        self.max_weight = Some(max_weight);
+        self
+    }
+
+    /// Weighs the entries for `max_weight`. Every entry weighs 1 by default.
+    pub fn weigher(mut self, weigher: impl Fn(&K, &V) -> u32 + Send + Sync + 'static) -> Self {
+        self.weigher = Some(Arc::new(weigher));
+        self
+    }
+
+    /// Entries expire once `duration` has passed since they were inserted.
+    pub fn time_to_live(mut self, duration: Duration) -> Self {
+        self.expiration.set_time_to_live(duration);
+        self
+    }
+
+    /// Entries expire once `duration` has passed since they were last read
+    /// or inserted.
+    pub fn time_to_idle(mut self, duration: Duration) -> Self {
+        self.expiration.set_time_to_idle(duration);
+        self
+    }
+
+    /// The clock used for expiration and for scheduling the maintenance.
+    /// Defaults to `SystemClock`.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Calls `listener` with every entry removed from the cache and the
+    /// cause. It is called while the policy is maintained, so it should be
+    /// quick; use `queued_eviction_listener` otherwise.
+    pub fn eviction_listener(
+        mut self,
+        listener: impl Fn(Arc<K>, Arc<V>, RemovalCause) + Send + Sync + 'static,
+    ) -> Self {
+        self.notifier = Some(Notifier::immediate(Arc::new(listener)));
+        self
+    }
+
+    /// Like `eviction_listener`, but `listener` is called on a dedicated
+    /// thread, so that a slow listener does not hold up the cache.
+    pub fn queued_eviction_listener(
+        mut self,
+        listener: impl Fn(Arc<K>, Arc<V>, RemovalCause) + Send + Sync + 'static,
+    ) -> Self
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+    {
+        self.notifier = Some(Notifier::queued(Arc::new(listener)));
+        self
+    }
+
+    /// Records the statistics returned by `stats`.
+    pub fn record_stats(mut self) -> Self {
+        self.record_stats = true;
+        self
+    }
+
+    /// What a writer does when the write buffer is full. Defaults to
+    /// `Backpressure::DrainInline`.
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Puts a doorkeeper Bloom filter in front of the frequency sketch, so
+    /// that keys read only once do not add noise to its counters. It helps
+    /// when many keys are never read again.
+    pub fn doorkeeper(mut self) -> Self {
+        self.doorkeeper = true;
+        self
+    }
+}
+
+impl<K, V, S> LFUCacheBuilder<K, V, S>
+where
+    K: Eq + Hash + Debug,
+    S: BuildHasher,
+{
+    pub fn build(self) -> LFUCache<K, V, S> {
+        LFUCache::from_builder(self)
+    }
+
+    pub fn build_async(self) -> AsyncLFUCache<K, V, S> {
+        AsyncLFUCache::from_cache(self.build())
+    }
+}
+
+/// Builds a `NaiveLFUCache`.
+pub struct NaiveLFUCacheBuilder<K, V> {
+    pub(crate) capacity: usize,
+    pub(crate) max_weight: Option<u64>,
+    pub(crate) weigher: Option<Weigher<K, V>>,
+    pub(crate) expiration: Expiration,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) record_stats: bool,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> NaiveLFUCacheBuilder<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            max_weight: None,
+            weigher: None,
+            expiration: Expiration::default(),
+            clock: Arc::new(SystemClock),
+            record_stats: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Bounds the cache by the total weight of its entries rather than by
+    /// their number. The capacity is still used to size the cache.
+    pub fn max_weight(mut self, max_weight: u64) -> Self {
+        self.max_weight = Some(max_weight);
+        self
+    }
+
+    /// Weighs the entries for `max_weight`. Every entry weighs 1 by default.
+    pub fn weigher(mut self, weigher: impl Fn(&K, &V) -> u32 + Send + Sync + 'static) -> Self {
+        self.weigher = Some(Arc::new(weigher));
+        self
+    }
+
+    /// Entries expire once `duration` has passed since they were inserted.
+    pub fn time_to_live(mut self, duration: Duration) -> Self {
+        self.expiration.set_time_to_live(duration);
+        self
+    }
+
+    /// Entries expire once `duration` has passed since they were last read
+    /// or inserted.
+    pub fn time_to_idle(mut self, duration: Duration) -> Self {
+        self.expiration.set_time_to_idle(duration);
+        self
+    }
+
+    /// The clock used for expiration.
+    /// Defaults to `SystemClock`.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Records the statistics returned by `stats`.
+    pub fn record_stats(mut self) -> Self {
+        self.record_stats = true;
+        self
+    }
+}
+
+impl<K, V> NaiveLFUCacheBuilder<K, V>
+where
+    K: Debug + Eq + Hash + Clone,
+{
+    pub fn build(self) -> NaiveLFUCache<K, V> {
+        NaiveLFUCache::from_builder(self)
+    }
+}
+
+/// Builds an unbounded `Cache`.
+pub struct CacheBuilder<K, V, S = RandomState> {
+    pub(crate) build_hasher: S,
+    pub(crate) expiration: Expiration,
+    pub(crate) clock: Arc<dyn Clock>,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> CacheBuilder<K, V, RandomState> {
+    pub fn new() -> Self {
+        Self {
+            build_hasher: RandomState::default(),
+            expiration: Expiration::default(),
+            clock: Arc::new(SystemClock),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V> Default for CacheBuilder<K, V, RandomState> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, S> CacheBuilder<K, V, S> {
+    pub fn hasher<S2>(self, build_hasher: S2) -> CacheBuilder<K, V, S2> {
+        CacheBuilder {
+            build_hasher,
+            expiration: self.expiration,
+            clock: self.clock,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Entries expire once `duration` has passed since they were inserted.
+    pub fn time_to_live(mut self, duration: Duration) -> Self {
+        self.expiration.set_time_to_live(duration);
+        self
+    }
+
+    /// Entries expire once `duration` has passed since they were last read
+    /// or inserted.
+    pub fn time_to_idle(mut self, duration: Duration) -> Self {
+        self.expiration.set_time_to_idle(duration);
+        self
+    }
+
+    /// The clock used for expiration.
+    /// Defaults to `SystemClock`.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+impl<K, V, S> CacheBuilder<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    pub fn build(self) -> Cache<K, V, S> {
+        Cache::from_builder(self)
+    }
+}
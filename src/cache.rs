@@ -1,38 +1,351 @@
-use cht::HashMap;
+use crate::builder::CacheBuilder;
+use crate::expiration::{EntryTimes, Expiration, TimeSource};
+use crate::value_initializer::ValueInitializer;
 use crate::ConcurrentCache;
-use std::sync::Arc;
+use cht::HashMap;
+use parking_lot::Mutex;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+// The number of entries checked for expiration on each insert.
+const EXPIRATION_SWEEP_SIZE: usize = 4;
 
-pub struct Cache<K, V> {
-    store: HashMap<K, V>,
+type SweepQueue<K, V> = VecDeque<(Arc<K>, Weak<CacheEntry<V>>)>;
+
+struct CacheEntry<V> {
+    value: Arc<V>,
+    times: EntryTimes,
 }
 
-impl<K, V> Cache<K, V> {
+/// An unbounded concurrent cache which never evicts its entries, other than
+/// the expired ones.
+///
+/// It is a thin wrapper around a lock-free `cht::HashMap`, and is useful as a
+/// memo table behind the same `ConcurrentCache` trait as the LFU caches.
+pub struct Cache<K, V, S = RandomState> {
+    store: HashMap<Arc<K>, Arc<CacheEntry<V>>, S>,
+    value_initializer: ValueInitializer<K, V>,
+    expiration: Expiration,
+    time_source: TimeSource,
+    // The entries which may expire, in insertion order. Each insert checks a
+    // few of them, so that expired entries are removed without a full scan.
+    sweep_queue: Mutex<SweepQueue<K, V>>,
+}
+
+impl<K, V> Cache<K, V, RandomState>
+where
+    K: Eq + Hash,
+{
     pub fn new() -> Self {
+        Self::new_with_hasher(RandomState::default())
+    }
+
+    pub fn builder() -> CacheBuilder<K, V, RandomState> {
+        CacheBuilder::new()
+    }
+}
+
+impl<K, V> Default for Cache<K, V, RandomState>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, S> Cache<K, V, S>
+where
+    K: Eq + Hash,
+{
+    pub fn new_with_hasher(build_hasher: S) -> Self {
+        Self::from_builder(CacheBuilder::new().hasher(build_hasher))
+    }
+
+    pub(crate) fn from_builder(builder: CacheBuilder<K, V, S>) -> Self {
+        let clock = builder.clock;
         Self {
-            store: HashMap::default(),
+            store: HashMap::with_hasher(builder.build_hasher),
+            value_initializer: ValueInitializer::new(),
+            expiration: builder.expiration,
+            time_source: TimeSource::new(clock),
+            sweep_queue: Mutex::new(VecDeque::new()),
         }
     }
+
+    /// The number of entries, including the expired ones which have not been
+    /// removed yet.
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
 }
 
-impl<K,V> ConcurrentCache<K,V> for Cache<K,V> {
+impl<K, V, S> Cache<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    fn get_value(&self, key: &K) -> Option<Arc<V>> {
+        let now = self.time_source.now();
+        let entry = self.store.get(key)?;
+        if self.expiration.is_expired(&entry.times, now) {
+            self.store.remove_if(key, |_, e| Arc::ptr_eq(e, &entry));
+            return None;
+        }
+        entry.times.set_last_accessed(now);
+        Some(Arc::clone(&entry.value))
+    }
+
+    fn new_entry(&self, value: V, ttl: Option<Duration>) -> Arc<CacheEntry<V>> {
+        Arc::new(CacheEntry {
+            value: Arc::new(value),
+            times: EntryTimes::new(self.time_source.now(), ttl),
+        })
+    }
+
+    fn insert_entry(&self, key: Arc<K>, entry: Arc<CacheEntry<V>>) {
+        self.store.insert(Arc::clone(&key), Arc::clone(&entry));
+        self.on_insert(key, &entry);
+    }
+
+    /// Inserts the value unless `insert` has raced with the initializer, so
+    /// that every caller of `get_or_insert_with` gets the same value. An
+    /// expired value is replaced.
+    fn insert_if_absent(&self, key: &Arc<K>, value: V) -> Arc<V> {
+        let entry = self.new_entry(value, None);
+        let now = self.time_source.now();
+        let mut replaced = false;
+        let existing =
+            self.store
+                .insert_or_modify(Arc::clone(key), Arc::clone(&entry), |_, existing| {
+                    replaced = self.expiration.is_expired(&existing.times, now);
+                    if replaced {
+                        Arc::clone(&entry)
+                    } else {
+                        Arc::clone(existing)
+                    }
+                });
+        match existing {
+            Some(existing) if !replaced => Arc::clone(&existing.value),
+            _ => {
+                self.on_insert(Arc::clone(key), &entry);
+                Arc::clone(&entry.value)
+            }
+        }
+    }
+
+    fn on_insert(&self, key: Arc<K>, entry: &Arc<CacheEntry<V>>) {
+        if !self.expiration.is_enabled() && entry.times.ttl().is_none() {
+            return;
+        }
+        let mut queue = self.sweep_queue.lock();
+        queue.push_back((key, Arc::downgrade(entry)));
+        self.sweep(&mut queue);
+    }
+
+    /// Checks a few entries from the front of the queue. Removed or replaced
+    /// entries are dropped from the queue, expired ones are removed from the
+    /// map, and the others are moved to the back.
+    fn sweep(&self, queue: &mut SweepQueue<K, V>) {
+        let now = self.time_source.now();
+        for _ in 0..usize::min(EXPIRATION_SWEEP_SIZE, queue.len()) {
+            let (key, weak) = match queue.pop_front() {
+                Some(item) => item,
+                None => break,
+            };
+            let entry = match weak.upgrade() {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if self.expiration.is_expired(&entry.times, now) {
+                self.store.remove_if(&key, |_, e| Arc::ptr_eq(e, &entry));
+            } else {
+                queue.push_back((key, weak));
+            }
+        }
+    }
+}
+
+impl<K, V, S> ConcurrentCache<K, V> for Cache<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
     fn get(&self, key: &K) -> Option<Arc<V>> {
-        unimplemented!()
+        self.get_value(key)
     }
 
     fn get_or_insert(&self, key: K, default: V) -> Arc<V> {
-        unimplemented!()
+        self.get_or_insert_with(key, || default)
+    }
+
+    fn get_or_insert_with<F>(&self, key: K, default: F) -> Arc<V>
+    where
+        F: FnOnce() -> V,
+    {
+        if let Some(v) = self.get_value(&key) {
+            return v;
+        }
+
+        // Concurrent calls for the same key run only one of the initializers.
+        let key = Arc::new(key);
+        self.value_initializer.init_or_read(
+            &key,
+            || self.get_value(&key),
+            default,
+            |value| self.insert_if_absent(&key, value),
+        )
     }
 
-    fn get_or_insert_with<F>(&self, key: K, default: F) -> Arc<V> where
-        F: FnOnce() -> V {
-        unimplemented!()
+    fn try_get_or_insert_with<F, E>(&self, key: K, init: F) -> Result<Arc<V>, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+        E: Clone + Send + Sync + 'static,
+    {
+        if let Some(v) = self.get_value(&key) {
+            return Ok(v);
+        }
+
+        let key = Arc::new(key);
+        self.value_initializer.try_init_or_read(
+            &key,
+            || self.get_value(&key),
+            init,
+            |value| self.insert_if_absent(&key, value),
+        )
     }
 
     fn insert(&self, key: K, value: V) {
-        unimplemented!()
+        self.insert_entry(Arc::new(key), self.new_entry(value, None));
+    }
+
+    fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        self.insert_entry(Arc::new(key), self.new_entry(value, Some(ttl)));
     }
 
     fn remove(&self, key: &K) -> Option<Arc<V>> {
-        unimplemented!()
+        let now = self.time_source.now();
+        self.store
+            .remove(key)
+            .filter(|entry| !self.expiration.is_expired(&entry.times, now))
+            .map(|entry| Arc::clone(&entry.value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, ConcurrentCache};
+    use crate::clock::MockClock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn basics() {
+        let cache = Cache::new();
+        cache.insert("a", "alice");
+        cache.insert("b", "bob");
+        assert_eq!(cache.len(), 2);
+
+        assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
+        assert_eq!(cache.get(&"c"), None);
+
+        assert_eq!(cache.get_or_insert("a", "anna"), Arc::new("alice"));
+        assert_eq!(cache.get_or_insert_with("c", || "cindy"), Arc::new("cindy"));
+        assert_eq!(cache.get(&"c"), Some(Arc::new("cindy")));
+
+        assert_eq!(cache.remove(&"b"), Some(Arc::new("bob")));
+        assert_eq!(cache.remove(&"b"), None);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn expiration() {
+        let clock = MockClock::new();
+        let cache = Cache::builder()
+            .time_to_idle(Duration::from_secs(10))
+            .clock(clock.clone())
+            .build();
+        cache.insert("a", "alice");
+        cache.insert("b", "bob");
+        cache.insert_with_ttl("c", "cindy", Duration::from_secs(3));
+        assert_eq!(cache.get(&"c"), Some(Arc::new("cindy")));
+
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
+        assert_eq!(cache.get(&"c"), None);
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
+        assert_eq!(cache.get_or_insert("b", "bill"), Arc::new("bill"));
+        assert_eq!(cache.len(), 2);
+
+        // Inserts remove the expired entries nobody reads.
+        clock.advance(Duration::from_secs(10));
+        for i in 0..4 {
+            cache.insert_with_ttl("d", "david", Duration::from_secs(i + 1));
+        }
+        assert_eq!(cache.len(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn concurrent_get_or_insert_with() {
+        let cache = Arc::new(Cache::new());
+        let num_inits = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let handles = (0..8)
+            .map(|i| {
+                let (cache, num_inits, barrier) = (
+                    Arc::clone(&cache),
+                    Arc::clone(&num_inits),
+                    Arc::clone(&barrier),
+                );
+                thread::spawn(move || {
+                    barrier.wait();
+                    cache.get_or_insert_with("key", || {
+                        num_inits.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(50));
+                        i
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let values = handles
+            .into_iter()
+            .map(|h| h.join().expect("Failed to join"))
+            .collect::<Vec<_>>();
+
+        // Only one initializer has run, and every thread gets its value.
+        assert_eq!(num_inits.load(Ordering::SeqCst), 1);
+        let winner = cache.get(&"key").expect("No value");
+        assert!(values.iter().all(|v| Arc::ptr_eq(v, &winner)));
+    }
+
+    #[test]
+    fn try_get_or_insert_with() {
+        let cache = Cache::new();
+        assert_eq!(
+            cache.try_get_or_insert_with("a", || Err("Failed to load")),
+            Err("Failed to load")
+        );
+        assert_eq!(cache.get(&"a"), None);
+
+        assert_eq!(
+            cache.try_get_or_insert_with("a", || Ok::<_, &str>("alice")),
+            Ok(Arc::new("alice"))
+        );
+        assert_eq!(
+            cache.try_get_or_insert_with("a", || Err("Failed to load")),
+            Ok(Arc::new("alice"))
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use crate::expiration::nanos;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A monotonic time source for expiration and maintenance scheduling.
+pub trait Clock: Send + Sync {
+    /// The current time. It must never go backwards.
+    fn now(&self) -> Instant;
+}
+
+/// The default clock, backed by `Instant::now`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when it is advanced. Clones share the same time,
+/// so a test can keep one and give another to the cache.
+///
+/// ```
+/// use cache_rs::{ConcurrentCache, LFUCache, MockClock};
+/// use std::time::Duration;
+///
+/// let clock = MockClock::new();
+/// let cache = LFUCache::builder(100)
+///     .time_to_live(Duration::from_secs(60))
+///     .clock(clock.clone())
+///     .build();
+/// cache.insert("a", "alice");
+///
+/// clock.advance(Duration::from_secs(61));
+/// assert_eq!(cache.get(&"a"), None);
+/// ```
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    origin: Instant,
+    elapsed_nanos: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            elapsed_nanos: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed_nanos
+            .fetch_add(nanos(duration), Ordering::SeqCst);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.origin + Duration::from_nanos(self.elapsed_nanos.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, MockClock};
+    use std::time::Duration;
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::new();
+        let shared = clock.clone();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        shared.advance(Duration::from_secs(3));
+        assert_eq!(clock.now() - start, Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,498 @@
+use crate::linked_list::{CacheRegion, LinkedList, Node};
+use crate::timer_wheel::{TimerNodePtr, TimerWheel};
+use parking_lot::Mutex;
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+// Percentages of the capacity given to each region of the W-TinyLFU policy.
+const WINDOW_PERCENTAGE: u64 = 1;
+const PROTECTED_PERCENTAGE: u64 = 80; // of the main space
+
+pub(crate) struct DeqNode<K> {
+    // The map's key, to remove the entry from the map when it is evicted.
+    pub(crate) key: Arc<K>,
+    // The hash of the key, which the frequency sketch counts.
+    pub(crate) hash: u64,
+    pub(crate) region: CacheRegion,
+    pub(crate) weight: u64,
+    // The nodes of the entry which this node belongs to. They can be unlinked
+    // through it even after the entry has been removed from the map.
+    pub(crate) owner: SharedEntryNodes<K>,
+}
+
+pub(crate) type DeqNodePtr<K> = NonNull<Node<DeqNode<K>>>;
+pub(crate) type WriteNodePtr<K> = NonNull<Node<NodeOwner<K>>>;
+pub(crate) type ExpiryNodePtr<K> = TimerNodePtr<NodeOwner<K>>;
+
+/// The entry which a node of the write order or the timer wheel belongs to.
+pub(crate) struct NodeOwner<K> {
+    pub(crate) key: Arc<K>,
+    pub(crate) nodes: SharedEntryNodes<K>,
+}
+
+impl<K> Clone for NodeOwner<K> {
+    fn clone(&self) -> Self {
+        Self {
+            key: Arc::clone(&self.key),
+            nodes: Arc::clone(&self.nodes),
+        }
+    }
+}
+
+/// The positions of an entry in the queues of the `Deques`.
+pub(crate) struct EntryNodes<K> {
+    pub(crate) access: Option<DeqNodePtr<K>>,
+    pub(crate) write: Option<WriteNodePtr<K>>,
+    pub(crate) expiry: Option<ExpiryNodePtr<K>>,
+}
+
+pub(crate) type SharedEntryNodes<K> = Arc<Mutex<EntryNodes<K>>>;
+
+impl<K> Default for EntryNodes<K> {
+    fn default() -> Self {
+        Self {
+            access: None,
+            write: None,
+            expiry: None,
+        }
+    }
+}
+
+/// Access order queues of the W-TinyLFU policy.
+///
+/// New entries are admitted to the LRU `window`. Entries evicted from the
+/// window become candidates for the main space, which is a segmented LRU
+/// made of `probation` and `protected`. An entry in probation is promoted to
+/// protected when it is accessed again.
+///
+/// The capacities of the regions are weights. Each region keeps the total
+/// weight of its entries.
+///
+/// For expiration, entries are also kept in write order, or in a timer wheel
+/// when they have their own time-to-live.
+pub(crate) struct Deques<K> {
+    window: LinkedList<DeqNode<K>>,
+    probation: LinkedList<DeqNode<K>>,
+    protected: LinkedList<DeqNode<K>>,
+    write_order: LinkedList<NodeOwner<K>>,
+    timer_wheel: TimerWheel<NodeOwner<K>>,
+    window_weight: u64,
+    probation_weight: u64,
+    protected_weight: u64,
+    window_capacity: u64,
+    main_capacity: u64,
+    protected_capacity: u64,
+}
+
+impl<K> Deques<K> {
+    pub(crate) fn new(capacity: u64) -> Self {
+        let window_capacity = if capacity == 0 {
+            0
+        } else {
+            u64::max(percentage_of(capacity, WINDOW_PERCENTAGE), 1)
+        };
+        let main_capacity = capacity - window_capacity;
+        Self {
+            window: LinkedList::new(),
+            probation: LinkedList::new(),
+            protected: LinkedList::new(),
+            write_order: LinkedList::new(),
+            timer_wheel: TimerWheel::new(0),
+            window_weight: 0,
+            probation_weight: 0,
+            protected_weight: 0,
+            window_capacity,
+            main_capacity,
+            protected_capacity: percentage_of(main_capacity, PROTECTED_PERCENTAGE),
+        }
+    }
+
+    pub(crate) fn main_weight(&self) -> u64 {
+        self.probation_weight + self.protected_weight
+    }
+
+    #[cfg(any(test, feature = "prometheus"))]
+    pub(crate) fn total_weight(&self) -> u64 {
+        self.window_weight + self.main_weight()
+    }
+
+    /// The number of nodes in the access order.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+
+    pub(crate) fn main_capacity(&self) -> u64 {
+        self.main_capacity
+    }
+
+    pub(crate) fn window_capacity(&self) -> u64 {
+        self.window_capacity
+    }
+
+    /// Grows the window by `delta`, or shrinks it if negative, taking the
+    /// room from the main space or giving it back. The window keeps room for
+    /// at least one entry, and the main space too when possible.
+    ///
+    /// A growing window takes over the main space's entries from the front,
+    /// as long as they fit. A shrinking window may overflow, and must be
+    /// evicted from.
+    pub(crate) fn resize_window(&mut self, delta: i64) {
+        let capacity = self.window_capacity + self.main_capacity;
+        let min = u64::min(capacity, 1);
+        let max = u64::max(capacity.saturating_sub(1), min);
+        // In i128, as capacities may not fit in an i64.
+        let window_capacity = (i128::from(self.window_capacity) + i128::from(delta))
+            .clamp(i128::from(min), i128::from(max)) as u64;
+        self.window_capacity = window_capacity;
+        self.main_capacity = capacity - window_capacity;
+        self.protected_capacity = percentage_of(self.main_capacity, PROTECTED_PERCENTAGE);
+
+        loop {
+            let next = self
+                .probation
+                .front_node()
+                .or_else(|| self.protected.front_node());
+            let mut node = match next {
+                Some(node) => node,
+                None => break,
+            };
+            let element = unsafe { node.as_mut().element_mut() };
+            if self.window_weight + element.weight > self.window_capacity {
+                break;
+            }
+            *self.weight_of_mut(element.region) -= element.weight;
+            self.window_weight += element.weight;
+            let region = std::mem::replace(&mut element.region, CacheRegion::Window);
+            unsafe {
+                match region {
+                    CacheRegion::MainProbation => {
+                        self.probation.move_to_back_of(node, &mut self.window)
+                    }
+                    _ => self.protected.move_to_back_of(node, &mut self.window),
+                }
+            }
+        }
+        self.demote_protected_overflow();
+    }
+
+    pub(crate) fn is_window_overflowed(&self) -> bool {
+        self.window_weight > self.window_capacity
+    }
+
+    /// The least recently used entry of the window.
+    pub(crate) fn window_front(&self) -> Option<DeqNodePtr<K>> {
+        self.window.front_node()
+    }
+
+    /// The entries of the main space in the order it evicts them: probation
+    /// from the least recently used, then protected.
+    pub(crate) fn main_victims(&self) -> impl Iterator<Item = DeqNodePtr<K>> + '_ {
+        let probation = std::iter::successors(self.probation.front_node(), |node| unsafe {
+            node.as_ref().next_node()
+        });
+        let protected = std::iter::successors(self.protected.front_node(), |node| unsafe {
+            node.as_ref().next_node()
+        });
+        probation.chain(protected)
+    }
+
+    /// The least recently used entry of the region.
+    pub(crate) fn front_of(&self, region: CacheRegion) -> Option<DeqNodePtr<K>> {
+        match region {
+            CacheRegion::Window => self.window.front_node(),
+            CacheRegion::MainProbation => self.probation.front_node(),
+            CacheRegion::MainProtected => self.protected.front_node(),
+        }
+    }
+
+    /// The least recently written entry.
+    pub(crate) fn write_order_front(&self) -> Option<WriteNodePtr<K>> {
+        self.write_order.front_node()
+    }
+
+    /// Advances the timer wheel to `now`. The timers which are due can be
+    /// taken from `due_timer`.
+    pub(crate) fn advance_timers(&mut self, now: u64) {
+        self.timer_wheel.advance(now);
+    }
+
+    /// A timer which is due. It must be unlinked or rescheduled.
+    pub(crate) fn due_timer(&self) -> Option<ExpiryNodePtr<K>> {
+        self.timer_wheel.due_front()
+    }
+
+    pub(crate) fn push_window(
+        &mut self,
+        key: Arc<K>,
+        hash: u64,
+        weight: u64,
+        owner: SharedEntryNodes<K>,
+    ) -> DeqNodePtr<K> {
+        let node = DeqNode {
+            key,
+            hash,
+            region: CacheRegion::Window,
+            weight,
+            owner,
+        };
+        self.window_weight += weight;
+        self.window.push_back(node).expect("No node was pushed")
+    }
+
+    /// Updates the weight of an entry whose value has been replaced.
+    ///
+    /// # Safety
+    ///
+    /// `node` must be a node of these deques.
+    pub(crate) unsafe fn set_weight(&mut self, mut node: DeqNodePtr<K>, weight: u64) {
+        let element = node.as_mut().element_mut();
+        let region_weight = self.weight_of_mut(element.region);
+        *region_weight = *region_weight - element.weight + weight;
+        element.weight = weight;
+    }
+
+    pub(crate) fn push_write_order(&mut self, owner: NodeOwner<K>) -> WriteNodePtr<K> {
+        self.write_order
+            .push_back(owner)
+            .expect("No node was pushed")
+    }
+
+    pub(crate) fn schedule_expiry(&mut self, owner: NodeOwner<K>, time: u64) -> ExpiryNodePtr<K> {
+        self.timer_wheel.schedule(owner, time)
+    }
+
+    /// Moves the entry's timer to `time`, or cancels it if `time` is `None`.
+    ///
+    /// # Safety
+    ///
+    /// The nodes must be nodes of these deques.
+    pub(crate) unsafe fn reschedule_expiry(
+        &mut self,
+        nodes: &mut EntryNodes<K>,
+        time: Option<u64>,
+    ) {
+        match (nodes.expiry, time) {
+            (Some(node), Some(time)) => self.timer_wheel.reschedule(node, time),
+            (Some(node), None) => {
+                self.timer_wheel.unlink(node);
+                nodes.expiry = None;
+            }
+            (None, _) => (),
+        }
+    }
+
+    /// Moves a window entry to the back of probation.
+    ///
+    /// # Safety
+    ///
+    /// `node` must be a node in the window of these deques.
+    pub(crate) unsafe fn move_to_probation(&mut self, mut node: DeqNodePtr<K>) {
+        debug_assert_eq!(node.as_ref().element().region, CacheRegion::Window);
+        let element = node.as_mut().element_mut();
+        element.region = CacheRegion::MainProbation;
+        self.window_weight -= element.weight;
+        self.probation_weight += element.weight;
+        self.window.move_to_back_of(node, &mut self.probation);
+    }
+
+    /// Updates the recency of the entry, promoting it to protected when it is
+    /// in probation.
+    ///
+    /// # Safety
+    ///
+    /// `node` must be a node of these deques.
+    pub(crate) unsafe fn on_access(&mut self, mut node: DeqNodePtr<K>) {
+        match node.as_ref().element().region {
+            CacheRegion::Window => self.window.move_to_back(Some(node)),
+            CacheRegion::MainProtected => self.protected.move_to_back(Some(node)),
+            CacheRegion::MainProbation => {
+                let element = node.as_mut().element_mut();
+                element.region = CacheRegion::MainProtected;
+                self.probation_weight -= element.weight;
+                self.protected_weight += element.weight;
+                self.probation.move_to_back_of(node, &mut self.protected);
+                self.demote_protected_overflow();
+            }
+        }
+    }
+
+    /// Unlinks the node and returns its element.
+    ///
+    /// # Safety
+    ///
+    /// `node` must be a node of these deques. It is deallocated by this call.
+    pub(crate) unsafe fn unlink(&mut self, node: DeqNodePtr<K>) -> DeqNode<K> {
+        let DeqNode { region, weight, .. } = *node.as_ref().element();
+        *self.weight_of_mut(region) -= weight;
+        match region {
+            CacheRegion::Window => self.window.remove(node),
+            CacheRegion::MainProbation => self.probation.remove(node),
+            CacheRegion::MainProtected => self.protected.remove(node),
+        }
+    }
+
+    /// Unlinks the entry from all the queues it is in.
+    ///
+    /// # Safety
+    ///
+    /// The nodes must be nodes of these deques.
+    pub(crate) unsafe fn unlink_entry(&mut self, nodes: &mut EntryNodes<K>) {
+        if let Some(node) = nodes.access.take() {
+            self.unlink(node);
+        }
+        self.unlink_expiry(nodes);
+    }
+
+    /// Unlinks the entry from the write order and the timer wheel, keeping
+    /// its position in the access order.
+    ///
+    /// # Safety
+    ///
+    /// The nodes must be nodes of these deques.
+    pub(crate) unsafe fn unlink_expiry(&mut self, nodes: &mut EntryNodes<K>) {
+        if let Some(node) = nodes.write.take() {
+            self.write_order.remove(node);
+        }
+        if let Some(node) = nodes.expiry.take() {
+            self.timer_wheel.unlink(node);
+        }
+    }
+
+    fn demote_protected_overflow(&mut self) {
+        while self.protected_weight > self.protected_capacity {
+            if let Some(mut node) = self.protected.front_node() {
+                unsafe {
+                    let element = node.as_mut().element_mut();
+                    element.region = CacheRegion::MainProbation;
+                    self.protected_weight -= element.weight;
+                    self.probation_weight += element.weight;
+                    self.protected.move_to_back_of(node, &mut self.probation);
+                }
+            }
+        }
+    }
+
+    fn weight_of_mut(&mut self, region: CacheRegion) -> &mut u64 {
+        match region {
+            CacheRegion::Window => &mut self.window_weight,
+            CacheRegion::MainProbation => &mut self.probation_weight,
+            CacheRegion::MainProtected => &mut self.protected_weight,
+        }
+    }
+}
+
+// Computed without overflowing for capacities close to `u64::MAX`.
+fn percentage_of(capacity: u64, percentage: u64) -> u64 {
+    capacity / 100 * percentage + capacity % 100 * percentage / 100
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deques;
+    use crate::linked_list::CacheRegion;
+    use std::sync::Arc;
+
+    #[test]
+    fn segmented_lru() {
+        let mut deques = Deques::new(100);
+        assert_eq!(deques.main_capacity(), 99);
+
+        let a = deques.push_window(Arc::new("a"), 0, 1, Arc::default());
+        let b = deques.push_window(Arc::new("b"), 0, 1, Arc::default());
+        assert!(deques.is_window_overflowed());
+        assert_eq!(deques.window_front(), Some(a));
+
+        unsafe {
+            deques.move_to_probation(a);
+            assert!(!deques.is_window_overflowed());
+            assert_eq!(deques.main_victims().next(), Some(a));
+
+            // A second access promotes "a" to protected.
+            deques.on_access(a);
+            assert_eq!(a.as_ref().element().region, CacheRegion::MainProtected);
+            assert_eq!(deques.main_victims().next(), Some(a));
+
+            assert_eq!(*deques.unlink(b).key, "b");
+        }
+        assert_eq!(deques.main_weight(), 1);
+    }
+
+    #[test]
+    fn weights() {
+        let mut deques = Deques::new(100);
+        let a = deques.push_window(Arc::new("a"), 0, 10, Arc::default());
+        let b = deques.push_window(Arc::new("b"), 0, 30, Arc::default());
+        unsafe {
+            deques.move_to_probation(a);
+            deques.move_to_probation(b);
+            assert_eq!(deques.main_weight(), 40);
+            assert_eq!(deques.main_victims().collect::<Vec<_>>(), vec![a, b]);
+
+            // Protected holds up to 79. Promoting "a" demotes nothing, and
+            // "b" stays the last victim.
+            deques.on_access(a);
+            assert_eq!(deques.main_victims().collect::<Vec<_>>(), vec![b, a]);
+
+            deques.set_weight(a, 70);
+            assert_eq!(deques.main_weight(), 100);
+            deques.on_access(b);
+            // "a" is demoted to keep protected under its capacity.
+            assert_eq!(a.as_ref().element().region, CacheRegion::MainProbation);
+            assert_eq!(deques.main_victims().collect::<Vec<_>>(), vec![a, b]);
+
+            deques.unlink(a);
+        }
+        assert_eq!(deques.main_weight(), 30);
+        assert!(!deques.is_window_overflowed());
+    }
+
+    #[test]
+    fn resize_window() {
+        let mut deques = Deques::new(100);
+        let a = deques.push_window(Arc::new("a"), 0, 1, Arc::default());
+        let b = deques.push_window(Arc::new("b"), 0, 5, Arc::default());
+        unsafe {
+            deques.move_to_probation(a);
+            deques.move_to_probation(b);
+            deques.on_access(b);
+        }
+
+        // The window takes over "a", but "b" does not fit.
+        deques.resize_window(4);
+        assert_eq!(deques.window_capacity(), 5);
+        assert_eq!(deques.main_capacity(), 95);
+        assert_eq!(deques.window_front(), Some(a));
+        assert_eq!(deques.main_victims().collect::<Vec<_>>(), vec![b]);
+
+        deques.resize_window(-10);
+        assert_eq!(deques.window_capacity(), 1);
+        assert!(!deques.is_window_overflowed());
+
+        // The main space keeps room for one entry.
+        deques.resize_window(1_000);
+        assert_eq!(deques.window_capacity(), 99);
+        assert_eq!(deques.main_weight(), 0);
+
+        // A shrinking window is left to be evicted from.
+        deques.resize_window(-1_000);
+        assert_eq!(deques.window_capacity(), 1);
+        assert!(deques.is_window_overflowed());
+    }
+
+    #[test]
+    fn huge_capacity() {
+        let mut deques = Deques::<&str>::new(u64::MAX);
+        assert_eq!(deques.window_capacity(), u64::MAX / 100);
+        assert_eq!(deques.main_capacity(), u64::MAX - u64::MAX / 100);
+        let protected_capacity = u128::from(deques.main_capacity()) * 80 / 100;
+        assert_eq!(u128::from(deques.protected_capacity), protected_capacity);
+
+        deques.resize_window(i64::MAX);
+        assert_eq!(deques.window_capacity(), u64::MAX / 100 + i64::MAX as u64);
+        deques.resize_window(i64::MIN);
+        assert_eq!(deques.window_capacity(), u64::MAX / 100 - 1);
+        deques.resize_window(i64::MIN);
+        assert_eq!(deques.window_capacity(), 1);
+    }
+}
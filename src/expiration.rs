@@ -0,0 +1,138 @@
+use crate::clock::Clock;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Cache-wide expiration settings.
+///
+/// An entry expires when `time_to_live` has passed since it was inserted, or
+/// when `time_to_idle` has passed since it was last read or inserted. A
+/// time-to-live given to `insert_with_ttl` overrides the cache-wide one.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Expiration {
+    time_to_live: Option<Duration>,
+    time_to_idle: Option<Duration>,
+}
+
+impl Expiration {
+    pub(crate) fn set_time_to_live(&mut self, duration: Duration) {
+        self.time_to_live = Some(duration);
+    }
+
+    pub(crate) fn set_time_to_idle(&mut self, duration: Duration) {
+        self.time_to_idle = Some(duration);
+    }
+
+    /// Whether an entry without its own time-to-live can ever expire.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.time_to_live.is_some() || self.time_to_idle.is_some()
+    }
+
+    pub(crate) fn time_to_live(&self) -> Option<Duration> {
+        self.time_to_live
+    }
+
+    pub(crate) fn time_to_idle(&self) -> Option<Duration> {
+        self.time_to_idle
+    }
+
+    pub(crate) fn is_expired(&self, times: &EntryTimes, now: u64) -> bool {
+        let ttl = times.ttl.or(self.time_to_live);
+        let expired_by_ttl =
+            ttl.is_some_and(|ttl| times.last_modified.saturating_add(nanos(ttl)) <= now);
+        let expired_by_tti = self
+            .time_to_idle
+            .is_some_and(|tti| times.last_accessed().saturating_add(nanos(tti)) <= now);
+        expired_by_ttl || expired_by_tti
+    }
+}
+
+/// The timestamps of a cache entry, in nanoseconds since the cache was
+/// created. Entries are replaced rather than updated on insert, so only the
+/// access time changes.
+pub(crate) struct EntryTimes {
+    last_modified: u64,
+    last_accessed: AtomicU64,
+    // The time-to-live given to `insert_with_ttl`.
+    ttl: Option<Duration>,
+}
+
+impl EntryTimes {
+    pub(crate) fn new(now: u64, ttl: Option<Duration>) -> Self {
+        Self {
+            last_modified: now,
+            last_accessed: AtomicU64::new(now),
+            ttl,
+        }
+    }
+
+    pub(crate) fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    /// The time this entry expires because of its own time-to-live.
+    pub(crate) fn expiration_time(&self) -> Option<u64> {
+        self.ttl
+            .map(|ttl| self.last_modified.saturating_add(nanos(ttl)))
+    }
+
+    pub(crate) fn last_accessed(&self) -> u64 {
+        self.last_accessed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_last_accessed(&self, now: u64) {
+        self.last_accessed.store(now, Ordering::Relaxed);
+    }
+}
+
+/// Measures the time elapsed since a cache was created.
+pub(crate) struct TimeSource {
+    clock: Arc<dyn Clock>,
+    origin: Instant,
+}
+
+impl TimeSource {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        let origin = clock.now();
+        Self { clock, origin }
+    }
+
+    /// Nanoseconds since the cache was created.
+    pub(crate) fn now(&self) -> u64 {
+        nanos(self.clock.now().saturating_duration_since(self.origin))
+    }
+}
+
+pub(crate) fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EntryTimes, Expiration};
+    use std::time::Duration;
+
+    const SEC: u64 = 1_000_000_000;
+
+    #[test]
+    fn is_expired() {
+        let mut expiration = Expiration::default();
+        expiration.set_time_to_live(Duration::from_secs(10));
+        let times = EntryTimes::new(SEC, None);
+        assert!(!expiration.is_expired(&times, 10 * SEC));
+        assert!(expiration.is_expired(&times, 11 * SEC));
+
+        // The entry's own time-to-live overrides the cache-wide one.
+        let times = EntryTimes::new(SEC, Some(Duration::from_secs(20)));
+        assert!(!expiration.is_expired(&times, 11 * SEC));
+        assert!(expiration.is_expired(&times, 21 * SEC));
+
+        let mut expiration = Expiration::default();
+        expiration.set_time_to_idle(Duration::from_secs(5));
+        let times = EntryTimes::new(SEC, None);
+        times.set_last_accessed(4 * SEC);
+        assert!(!expiration.is_expired(&times, 8 * SEC));
+        assert!(expiration.is_expired(&times, 9 * SEC));
+    }
+}
@@ -0,0 +1,302 @@
+use rand::Rng;
+
+// The seeds of the four hash functions, one per counter of a key.
+const SEEDS: [u64; 4] = [
+    0xc3a5_c85c_97cb_3127,
+    0xb492_b66f_be98_f273,
+    0x9ae1_6a3b_2f90_404f,
+    0xcbf2_9ce4_8422_2325,
+];
+const RESET_MASK: u64 = 0x7777_7777_7777_7777;
+const ONE_MASK: u64 = 0x1111_1111_1111_1111;
+const MAX_COUNT: u64 = 15;
+// A candidate at least this frequent is warm, and is occasionally admitted in
+// place of a more frequent victim.
+const WARM_FREQUENCY: u64 = 6;
+// The odds of admitting a warm candidate which would be rejected.
+const WARM_ADMISSION_RATIO: u32 = 128;
+// The doorkeeper's bits per sample, and the number of bits set per key.
+const DOORKEEPER_BITS_PER_SAMPLE: usize = 4;
+const DOORKEEPER_HASHES: u64 = 3;
+
+/// A count-min sketch of 4-bit counters which ages, as described by TinyLFU.
+///
+/// Each key is counted by four counters, in the same group of four counters
+/// of four different words. Incrementing only raises the counters which are
+/// at the minimum (conservative update), and once the number of increments
+/// reaches the sample size, every counter is halved so that keys which were
+/// popular in the past make room for the ones popular now.
+///
+/// With a doorkeeper, the first time a key is counted after a reset only
+/// sets it in the doorkeeper, and the counters only see the keys counted
+/// again. Keys counted once then leave no noise in the counters.
+///
+/// The hashes are mixed with a seed, so that which keys share counters
+/// differs from one sketch to another.
+pub(crate) struct FrequencySketch {
+    table: Box<[u64]>,
+    seed: u64,
+    doorkeeper: Option<Doorkeeper>,
+    sample_size: usize,
+    // The number of increments since the last reset.
+    size: usize,
+}
+
+impl FrequencySketch {
+    /// Creates a sketch for a cache of `capacity` entries.
+    pub(crate) fn new(capacity: usize, seed: u64) -> Self {
+        let capacity = capacity.clamp(1, 1 << 30);
+        Self {
+            table: vec![0; capacity.next_power_of_two()].into_boxed_slice(),
+            seed,
+            doorkeeper: None,
+            sample_size: 10 * capacity,
+            size: 0,
+        }
+    }
+
+    /// The number of increments after which the counters are halved.
+    pub(crate) fn sample_size(&self) -> usize {
+        self.sample_size
+    }
+
+    pub(crate) fn with_doorkeeper(mut self) -> Self {
+        self.doorkeeper = Some(Doorkeeper::new(
+            self.sample_size * DOORKEEPER_BITS_PER_SAMPLE,
+        ));
+        self
+    }
+
+    /// The estimated number of times the key has been counted since the
+    /// counters were last halved, up to 15, or 16 with a doorkeeper.
+    pub(crate) fn frequency(&self, hash: u64) -> u8 {
+        let hash = hash ^ self.seed;
+        let start = Self::group(hash);
+        let count = (0..4)
+            .map(|i| self.count(self.index_of(hash, i), start + i))
+            .min()
+            .unwrap_or_default() as u8;
+        match &self.doorkeeper {
+            Some(doorkeeper) if doorkeeper.contains(hash) => count + 1,
+            _ => count,
+        }
+    }
+
+    pub(crate) fn increment(&mut self, hash: u64) {
+        let hash = hash ^ self.seed;
+        if let Some(doorkeeper) = &mut self.doorkeeper {
+            if doorkeeper.insert(hash) {
+                return self.sample();
+            }
+        }
+
+        let start = Self::group(hash);
+        let mut counters = [(0, 0); 4];
+        for (i, counter) in counters.iter_mut().enumerate() {
+            *counter = (self.index_of(hash, i), start + i);
+        }
+        let min = counters
+            .iter()
+            .map(|&(index, offset)| self.count(index, offset))
+            .min()
+            .unwrap_or_default();
+        if min == MAX_COUNT {
+            return;
+        }
+
+        for &(index, offset) in &counters {
+            if self.count(index, offset) == min {
+                self.table[index] += 1 << (offset << 2);
+            }
+        }
+        self.sample();
+    }
+
+    fn sample(&mut self) {
+        self.size += 1;
+        if self.size >= self.sample_size {
+            self.reset();
+        }
+    }
+
+    /// Halves every counter, and clears the doorkeeper.
+    fn reset(&mut self) {
+        if let Some(doorkeeper) = &mut self.doorkeeper {
+            doorkeeper.clear();
+        }
+        let mut odd = 0;
+        for word in self.table.iter_mut() {
+            odd += (*word & ONE_MASK).count_ones() as usize;
+            *word = (*word >> 1) & RESET_MASK;
+        }
+        // Each key has four counters, whose halves were rounded down.
+        self.size = self.size.saturating_sub(odd >> 2) >> 1;
+    }
+
+    fn count(&self, index: usize, offset: usize) -> u64 {
+        (self.table[index] >> (offset << 2)) & MAX_COUNT
+    }
+
+    // Selects one of the four groups of four counters in a word.
+    fn group(hash: u64) -> usize {
+        ((spread(hash) & 3) << 2) as usize
+    }
+
+    fn index_of(&self, hash: u64, i: usize) -> usize {
+        let mut h = spread(hash).wrapping_add(SEEDS[i]).wrapping_mul(SEEDS[i]);
+        h = h.wrapping_add(h >> 32);
+        h as usize & (self.table.len() - 1)
+    }
+}
+
+/// A Bloom filter of the keys counted since the last reset.
+struct Doorkeeper {
+    bits: Box<[u64]>,
+}
+
+impl Doorkeeper {
+    fn new(num_bits: usize) -> Self {
+        let num_words = (num_bits.next_power_of_two() / 64).max(1);
+        Self {
+            bits: vec![0; num_words].into_boxed_slice(),
+        }
+    }
+
+    fn contains(&self, hash: u64) -> bool {
+        self.indexes(hash)
+            .all(|(word, bit)| self.bits[word] & (1 << bit) != 0)
+    }
+
+    /// Sets the key, and returns whether it was not set.
+    fn insert(&mut self, hash: u64) -> bool {
+        let mut inserted = false;
+        for (word, bit) in self.indexes(hash) {
+            inserted |= self.bits[word] & (1 << bit) == 0;
+            self.bits[word] |= 1 << bit;
+        }
+        inserted
+    }
+
+    fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+
+    // Derives the bits of the key from two halves of its hash.
+    fn indexes(&self, hash: u64) -> impl Iterator<Item = (usize, u64)> {
+        let mask = (self.bits.len() * 64 - 1) as u64;
+        let h = spread(hash.rotate_left(32));
+        let (h1, h2) = (h & 0xffff_ffff, h >> 32);
+        (0..DOORKEEPER_HASHES).map(move |i| {
+            let index = h1.wrapping_add(i.wrapping_mul(h2)) & mask;
+            ((index >> 6) as usize, index & 63)
+        })
+    }
+}
+
+/// Whether to admit a candidate in place of victims with the given estimated
+/// frequencies. The more frequent side wins, except that a warm candidate is
+/// admitted at random once in a while. Otherwise an attacker who inflates the
+/// frequency of a victim, e.g. with keys sharing its counters, could keep it
+/// in the cache for good.
+pub(crate) fn admit(candidate_frequency: u64, victims_frequency: u64) -> bool {
+    candidate_frequency > victims_frequency
+        || (candidate_frequency >= WARM_FREQUENCY
+            && rand::thread_rng().gen_ratio(1, WARM_ADMISSION_RATIO))
+}
+
+// Mixes the bits of a hash which may come from a weak hasher.
+fn spread(hash: u64) -> u64 {
+    let mut h = hash;
+    h = (h ^ (h >> 33)).wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h = (h ^ (h >> 33)).wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{admit, FrequencySketch};
+
+    #[test]
+    fn increment_and_saturate() {
+        let mut sketch = FrequencySketch::new(512, 0);
+        assert_eq!(sketch.frequency(42), 0);
+        for i in 1..=5 {
+            sketch.increment(42);
+            assert_eq!(sketch.frequency(42), i);
+        }
+        for _ in 0..20 {
+            sketch.increment(42);
+        }
+        assert_eq!(sketch.frequency(42), 15);
+        // Increments of a saturated key are not sampled.
+        assert_eq!(sketch.size, 15);
+    }
+
+    #[test]
+    fn doorkeeper() {
+        let mut sketch = FrequencySketch::new(64, 0).with_doorkeeper();
+        sketch.increment(42);
+        assert_eq!(sketch.frequency(42), 1);
+        sketch.increment(42);
+        assert_eq!(sketch.frequency(42), 2);
+
+        // Keys counted once only set the doorkeeper.
+        for hash in 1_000..1_100 {
+            sketch.increment(hash);
+        }
+        let counts = sketch
+            .table
+            .iter()
+            .map(|word| word.count_ones())
+            .sum::<u32>();
+        assert_eq!(counts, 4);
+
+        // Resetting clears the doorkeeper and halves the counters.
+        sketch.reset();
+        assert_eq!(sketch.frequency(42), 0);
+        assert_eq!(sketch.frequency(1_000), 0);
+    }
+
+    #[test]
+    fn reset() {
+        let mut sketch = FrequencySketch::new(64, 0);
+        for _ in 0..10 {
+            sketch.increment(42);
+        }
+        // Count distinct keys until the counters are halved.
+        let mut hash = 1_000;
+        loop {
+            let size = sketch.size;
+            sketch.increment(hash);
+            hash += 1;
+            if sketch.size < size {
+                break;
+            }
+        }
+        assert!(sketch.size <= sketch.sample_size / 2);
+        let frequency = sketch.frequency(42);
+        assert!((5..=7).contains(&frequency), "{}", frequency);
+    }
+
+    #[test]
+    fn seed() {
+        // Keys which share a counter in one sketch do not in another.
+        let mut sketch = FrequencySketch::new(64, 0);
+        let mut other = FrequencySketch::new(64, 0x9e37_79b9_7f4a_7c15);
+        for hash in 0..64 {
+            sketch.increment(hash);
+            other.increment(hash);
+        }
+        assert_ne!(sketch.table, other.table);
+    }
+
+    #[test]
+    fn warm_admission() {
+        let admitted = |candidate| (0..10_000).filter(|_| admit(candidate, 15)).count();
+        assert!(admit(8, 7));
+        assert_eq!(admitted(5), 0);
+        // About 1 in 128.
+        let warm = admitted(6);
+        assert!((20..200).contains(&warm), "{}", warm);
+    }
+}
@@ -0,0 +1,145 @@
+// A change of the hit rate at least this large restarts the climb with the
+// initial step size.
+const RESTART_THRESHOLD: f64 = 0.05;
+// The initial step, as a fraction of the capacity.
+const STEP_PERCENT: f64 = 0.0625;
+// Each step is this much smaller than the previous one, unless the climb is
+// restarted.
+const STEP_DECAY_RATE: f64 = 0.98;
+
+/// Adapts the size of the window to the workload, as in Caffeine.
+///
+/// The hit rate is sampled over a number of reads. If it went up since the
+/// previous sample, the window keeps being resized in the same direction,
+/// otherwise the direction is reversed. The steps decay so that the window
+/// settles, and start over when the hit rate changes sharply.
+pub(crate) struct HillClimber {
+    sample_size: u64,
+    hits: u64,
+    misses: u64,
+    previous_hit_rate: f64,
+    // Signed: positive steps grow the window.
+    step_size: f64,
+    initial_step_size: f64,
+    // Fractional, so that steps smaller than 1, with a small capacity, add up
+    // rather than being lost.
+    adjustment: f64,
+}
+
+impl HillClimber {
+    pub(crate) fn new(capacity: u64, sample_size: u64) -> Self {
+        let initial_step_size = STEP_PERCENT * capacity as f64;
+        Self {
+            sample_size,
+            hits: 0,
+            misses: 0,
+            previous_hit_rate: 0.0,
+            // The window starts small, and is first shrunk further.
+            step_size: -initial_step_size,
+            initial_step_size,
+            adjustment: 0.0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, hit: bool) {
+        if hit {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        if self.hits + self.misses >= self.sample_size {
+            self.climb();
+        }
+    }
+
+    /// Whether the window should be resized by at least one entry.
+    pub(crate) fn has_adjustment(&self) -> bool {
+        self.adjustment.abs() >= 1.0
+    }
+
+    /// Takes how much the window should grow, or shrink if negative, since
+    /// it was last taken. The fraction of an entry is kept for the next time.
+    pub(crate) fn take_adjustment(&mut self) -> i64 {
+        let whole = self.adjustment.trunc();
+        self.adjustment -= whole;
+        whole as i64
+    }
+
+    fn climb(&mut self) {
+        let hit_rate = self.hits as f64 / (self.hits + self.misses) as f64;
+        let change = hit_rate - self.previous_hit_rate;
+        let amount = if change >= 0.0 {
+            self.step_size
+        } else {
+            -self.step_size
+        };
+        self.step_size = if change.abs() >= RESTART_THRESHOLD {
+            self.initial_step_size.copysign(amount)
+        } else {
+            STEP_DECAY_RATE * amount
+        };
+        self.adjustment += amount;
+        self.previous_hit_rate = hit_rate;
+        self.hits = 0;
+        self.misses = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HillClimber;
+
+    fn sample(climber: &mut HillClimber, hits: u64) {
+        for i in 0..100 {
+            climber.record(i < hits);
+        }
+    }
+
+    #[test]
+    fn climb() {
+        let mut climber = HillClimber::new(1_000, 100);
+        sample(&mut climber, 50);
+        assert!(climber.has_adjustment());
+        assert_eq!(climber.take_adjustment(), -62);
+        assert!(!climber.has_adjustment());
+        assert_eq!(climber.take_adjustment(), 0);
+
+        // The hit rate fell sharply, so the window grows by a full step.
+        sample(&mut climber, 20);
+        assert_eq!(climber.take_adjustment(), 62);
+
+        // It rose slightly, so the window keeps growing, by decaying steps.
+        sample(&mut climber, 22);
+        assert_eq!(climber.take_adjustment(), 62);
+        sample(&mut climber, 23);
+        sample(&mut climber, 24);
+        assert_eq!(climber.take_adjustment(), 61 + 60);
+
+        // It fell slightly, so the window shrinks.
+        sample(&mut climber, 23);
+        assert_eq!(climber.take_adjustment(), -58);
+    }
+
+    #[test]
+    fn small_steps() {
+        // The steps are 0.625.
+        let mut climber = HillClimber::new(10, 100);
+        sample(&mut climber, 50);
+        assert_eq!(climber.take_adjustment(), 0);
+        sample(&mut climber, 20);
+        assert_eq!(climber.take_adjustment(), 0);
+
+        // The window grows once the steps add up to a whole entry.
+        sample(&mut climber, 20);
+        assert_eq!(climber.take_adjustment(), 0);
+        sample(&mut climber, 20);
+        assert_eq!(climber.take_adjustment(), 1);
+    }
+
+    #[test]
+    fn huge_capacity() {
+        let mut climber = HillClimber::new(u64::MAX, 100);
+        sample(&mut climber, 50);
+        assert_eq!(climber.take_adjustment(), -(1 << 60));
+    }
+}
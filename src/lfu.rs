@@ -1,127 +1,474 @@
+use crate::builder::{LFUCacheBuilder, Weigher};
+use crate::deques::{DeqNodePtr, Deques, NodeOwner, SharedEntryNodes};
+use crate::expiration::{EntryTimes, Expiration, TimeSource};
+use crate::frequency_sketch::{admit, FrequencySketch};
+use crate::hill_climber::HillClimber;
+use crate::linked_list::CacheRegion;
+use crate::maintenance::MaintenanceTask;
+#[cfg(feature = "prometheus")]
+use crate::metrics::{MetricsSnapshot, MetricsSource};
+use crate::notification::{Notifier, RemovalCause};
+use crate::read_buffer::{ReadBuffer, STRIPE_CAPACITY};
+use crate::stats::{CacheStats, Counter, StatsCounter};
+use crate::value_initializer::ValueInitializer;
 use crate::ConcurrentCache;
 
-use crate::lfu::WriteOp::{Insert, Remove};
-use count_min_sketch::CountMinSketch8;
-use crossbeam_channel::{Receiver, SendError, Sender};
+use crate::lfu::ReadOp::{ReadExisting, ReadMissing};
+use crate::lfu::WriteOp::{Remove, Upsert};
+use crossbeam_channel::{Receiver, SendTimeoutError, Sender, TrySendError};
 use parking_lot::{Mutex, MutexGuard, RwLock};
 use std::collections::hash_map::RandomState;
-use std::collections::HashSet;
 use std::fmt::Debug;
 use std::hash::{BuildHasher, Hash};
-use std::{collections::HashMap, hash, sync::Arc};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
 
-type Cache<K, V, S> = cht::HashMap<Arc<K>, Arc<V>, S>;
-type KeySet<K> = HashSet<Arc<K>>;
+type Cache<K, V, S> = cht::HashMap<Arc<K>, Arc<ValueEntry<K, V>>, SharedBuildHasher<S>>;
 
-const READ_LOG_SIZE: usize = 64;
 const WRITE_LOG_SIZE: usize = 256;
-const READ_LOG_HIGH_WATER_MARK: usize = 48; // 75% of READ_LOG_SIZE
+const READ_LOG_HIGH_WATER_MARK: usize = 48; // 75% of a read buffer stripe
 const WRITE_LOG_HIGH_WATER_MARK: usize = 128; // 50% of WRITE_LOG_SIZE
 
+// Pending ops are applied once this much time has passed since the last run,
+// even if the log is below the high water mark.
+const MAINTENANCE_INTERVAL_NANOS: u64 = 100_000; // 100 micro secs
+
+// How long `Backpressure::WaitThenDrain` waits for room before it tries to
+// apply the writes itself.
+const WAIT_TIMEOUT: Duration = Duration::from_millis(1);
+
+pub(crate) struct ValueEntry<K, V> {
+    value: Arc<V>,
+    times: EntryTimes,
+    // The positions of the key in the deques. An entry which replaces another
+    // one for the same key shares them, so that the policy keeps the key's
+    // recency. They are only accessed while the deques are locked, and are
+    // cleared once the key has been evicted or removed.
+    nodes: SharedEntryNodes<K>,
+}
+
+impl<K, V> ValueEntry<K, V> {
+    fn new(value: Arc<V>, times: EntryTimes) -> Self {
+        Self {
+            value,
+            times,
+            nodes: SharedEntryNodes::default(),
+        }
+    }
+
+    fn replacing(value: Arc<V>, times: EntryTimes, old: &Self) -> Self {
+        Self {
+            value,
+            times,
+            nodes: Arc::clone(&old.nodes),
+        }
+    }
+}
+
+// The deque nodes are only accessed while the deques are locked.
+unsafe impl<K: Send + Sync, V: Send + Sync> Send for ValueEntry<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for ValueEntry<K, V> {}
+
+// The reads carry the hash of the key, which is all the frequency sketch
+// needs.
 enum ReadOp<K, V> {
-    ReadExisting(K, Arc),
+    ReadExisting(u64, Arc<ValueEntry<K, V>>),
+    ReadMissing(u64),
 }
 
-enum WriteOp<K, V> {
-    Insert(K, V),
-    Remove(K),
+pub(crate) enum WriteOp<K, V> {
+    // An entry which has already been inserted into the map. The policy has
+    // yet to add it to the deques.
+    Upsert(Arc<K>, Arc<ValueEntry<K, V>>),
+    // An entry which has already been removed from the map. The policy has
+    // yet to unlink its nodes.
+    Remove(Arc<ValueEntry<K, V>>),
+}
+
+/// What a writer does when the write buffer is full, i.e. when the writes are
+/// coming in faster than the policy applies them. The writer's value is in
+/// the map by then.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Gives another thread, e.g. a `Maintainer`, up to a millisecond to
+    /// make room, then applies the pending writes itself unless another
+    /// thread is applying them, and tries again. It never waits
+    /// indefinitely, as no other thread may ever apply the writes.
+    WaitThenDrain,
+    /// Applies the pending writes itself, or yields while another thread is
+    /// applying them.
+    #[default]
+    DrainInline,
+    /// Gives up an insert, and removes its value from the cache with
+    /// `RemovalCause::Dropped`. It is counted in `CacheStats::dropped_writes`.
+    /// A removal is never given up.
+    Drop,
 }
 
-#[derive(Clone)]
 pub struct LFUCache<K, V, S> {
     inner: Arc<LFUInner<K, V, S>>,
-    read_op_ch: Sender<K>,
     write_op_ch: Sender<WriteOp<K, V>>,
 }
 
+impl<K, V, S> Clone for LFUCache<K, V, S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            write_op_ch: self.write_op_ch.clone(),
+        }
+    }
+}
+
 impl<K, V> LFUCache<K, V, RandomState>
 where
-    K: Clone + Eq + Hash + Debug,
+    K: Eq + Hash + Debug,
 {
     pub fn new(capacity: usize) -> Self {
-        let (r_snd, r_rcv) = crossbeam_channel::bounded(READ_LOG_SIZE);
-        let (w_snd, w_rcv) = crossbeam_channel::bounded(WRITE_LOG_SIZE);
-        Self {
-            inner: Arc::new(LFUInner::new(
-                capacity,
-                RandomState::default(),
-                r_rcv,
-                w_rcv,
-            )),
-            read_op_ch: r_snd,
-            write_op_ch: w_snd,
-        }
+        LFUCacheBuilder::new(capacity).build()
+    }
+
+    pub fn builder(capacity: usize) -> LFUCacheBuilder<K, V, RandomState> {
+        LFUCacheBuilder::new(capacity)
     }
 }
 
 impl<K, V, S> LFUCache<K, V, S>
 where
-    K: Clone + Eq + Hash + Debug,
+    K: Eq + Hash + Debug,
     S: BuildHasher,
 {
     pub fn new_with_hasher(capacity: usize, build_hasher: S) -> Self {
-        let (r_snd, r_rcv) = crossbeam_channel::bounded(READ_LOG_SIZE);
+        LFUCacheBuilder::new(capacity).hasher(build_hasher).build()
+    }
+
+    pub(crate) fn from_builder(builder: LFUCacheBuilder<K, V, S>) -> Self {
         let (w_snd, w_rcv) = crossbeam_channel::bounded(WRITE_LOG_SIZE);
         Self {
-            inner: Arc::new(LFUInner::new(capacity, build_hasher, r_rcv, w_rcv)),
-            read_op_ch: r_snd,
+            inner: Arc::new(LFUInner::new(builder, w_rcv)),
             write_op_ch: w_snd,
         }
     }
 
+    /// Applies the pending reads and writes, and removes the expired entries.
     pub fn sync(&self) {
-        let r_len = self.read_op_ch.len();
-        if r_len > 0 {
-            let r_lock = self.inner.reads_apply_lock.lock();
-            self.inner.apply_reads(r_lock, r_len);
+        self.inner.maintain();
+    }
+
+    /// A snapshot of the statistics. They are only recorded if the cache was
+    /// built with `record_stats`.
+    pub fn stats(&self) -> CacheStats {
+        self.inner.stats.snapshot()
+    }
+
+    /// The weight the window currently holds, at most. The rest of
+    /// `max_weight` is the main space. The policy resizes the window to the
+    /// workload.
+    pub fn window_capacity(&self) -> u64 {
+        self.inner.deques.lock().window_capacity()
+    }
+
+    /// Gives the snapshots collected by `CacheMetrics`, without keeping the
+    /// cache alive.
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn metrics_source(&self) -> MetricsSource
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let inner = WeakInner(Arc::downgrade(&self.inner));
+        Box::new(move || {
+            let inner = inner.0.upgrade()?;
+            let deqs = inner.deques.lock();
+            let (weighted_size, window_capacity) = (deqs.total_weight(), deqs.window_capacity());
+            drop(deqs);
+            Some(MetricsSnapshot {
+                entry_count: inner.cache.len() as u64,
+                weighted_size,
+                window_capacity,
+                stats: inner.stats.snapshot(),
+            })
+        })
+    }
+
+    /// Gives the passes run by a `Maintainer`, without keeping the cache
+    /// alive.
+    pub(crate) fn maintenance_task(&self) -> MaintenanceTask
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        let inner = WeakInner(Arc::downgrade(&self.inner));
+        Box::new(move || match inner.0.upgrade() {
+            Some(inner) => {
+                inner.maintain();
+                true
+            }
+            None => false,
+        })
+    }
+
+    fn record_read_op(&self, op: ReadOp<K, V>) {
+        match self.inner.read_buffer.offer(op) {
+            Some(len) => self.apply_reads_if_needed(len),
+            None => {
+                self.inner.stats.record(Counter::DroppedRead);
+                self.apply_reads_if_needed(STRIPE_CAPACITY);
+            }
         }
+    }
+
+    pub(crate) fn get_value(&self, key: &K) -> Option<Arc<V>> {
+        let now = self.inner.time_source.now();
+        self.inner
+            .get_entry(key)
+            .filter(|entry| !self.inner.is_expired(entry, now))
+            .map(|entry| Arc::clone(&entry.value))
+    }
 
-        let l_len = self.write_op_ch.len();
-        if l_len > 0 {
-            let w_lock = self.inner.writes_apply_lock.lock();
-            self.inner.apply_writes(w_lock, l_len);
+    /// Writes the entry to the map right away so that it is visible to other
+    /// threads. The policy admits it when the returned write op is applied.
+    ///
+    /// Returns the value in the map, which is the existing one if another
+    /// thread has inserted a value for the key and it has not expired. In
+    /// that case there is no write op to schedule.
+    pub(crate) fn insert_entry(&self, key: Arc<K>, value: V) -> (Arc<V>, Option<WriteOp<K, V>>) {
+        let now = self.inner.time_source.now();
+        let value = Arc::new(value);
+        let entry = Arc::new(ValueEntry::new(
+            Arc::clone(&value),
+            EntryTimes::new(now, None),
+        ));
+        let mut replacing = None;
+        let existing = self.inner.cache.insert_or_modify(
+            Arc::clone(&key),
+            Arc::clone(&entry),
+            |_, existing| {
+                if self.inner.is_expired(existing, now) {
+                    let times = EntryTimes::new(now, None);
+                    let new = Arc::new(ValueEntry::replacing(Arc::clone(&value), times, existing));
+                    replacing = Some(Arc::clone(&new));
+                    new
+                } else {
+                    replacing = None;
+                    Arc::clone(existing)
+                }
+            },
+        );
+        match (existing, replacing) {
+            (None, _) => {
+                self.inner.stats.record(Counter::Insert);
+                (value, Some(Upsert(key, entry)))
+            }
+            (Some(expired), Some(new)) => {
+                self.inner.stats.record(Counter::Insert);
+                self.inner.on_removal(&key, &expired, RemovalCause::Expired);
+                (value, Some(Upsert(key, new)))
+            }
+            (Some(existing), None) => (Arc::clone(&existing.value), None),
         }
     }
 
-    fn record_read_op(&self, key: &K) {
-        let _ = self.read_op_ch.try_send(key.clone());
-        self.apply_reads_if_needed();
+    /// Removes the entry from the map right away. The policy unlinks it when
+    /// the returned write op is applied.
+    ///
+    /// Returns the removed value, unless it had expired.
+    pub(crate) fn remove_entry(&self, key: &K) -> (Option<Arc<V>>, Option<WriteOp<K, V>>) {
+        let (key, entry) = match self.inner.cache.remove_entry(key) {
+            Some(removed) => removed,
+            None => return (None, None),
+        };
+        let value = if self.inner.is_expired(&entry, self.inner.time_source.now()) {
+            self.inner.on_removal(&key, &entry, RemovalCause::Expired);
+            None
+        } else {
+            self.inner.on_removal(&key, &entry, RemovalCause::Explicit);
+            Some(Arc::clone(&entry.value))
+        };
+        (value, Some(Remove(entry)))
     }
 
-    fn schedule_insert_op(&self, key: K, value: V) -> Result<(), SendError<WriteOp<K, V>>> {
-        let ch = &self.write_op_ch;
-        // NOTE: This will be blocked if the channel is full.
-        ch.send(WriteOp::Insert(key, value))?;
+    fn schedule_write_op(&self, op: WriteOp<K, V>) {
+        match self.write_op_ch.try_send(op) {
+            Ok(()) => {}
+            Err(TrySendError::Full(op)) => self.on_write_buffer_full(op),
+            Err(TrySendError::Disconnected(_)) => panic!("Failed to schedule a write op"),
+        }
         self.apply_reads_writes_if_needed();
-        Ok(())
     }
 
-    fn schedule_remove_op(&self, key: &K) -> Result<(), SendError<WriteOp<K, V>>> {
-        let ch = &self.write_op_ch;
-        // TODO: Send a hash rather than the key itself so that we can avoid clone().
-        // NOTE: This will be blocked if the channel is full.
-        ch.send(WriteOp::Remove(key.clone()))?;
+    fn on_write_buffer_full(&self, mut op: WriteOp<K, V>) {
+        loop {
+            // The thread applying the writes, e.g. from an eviction listener,
+            // would wait for itself.
+            if self.inner.is_applying_writes() {
+                return self.drop_write_op(op);
+            }
+            let result = match self.inner.backpressure {
+                Backpressure::WaitThenDrain => {
+                    match self.write_op_ch.send_timeout(op, WAIT_TIMEOUT) {
+                        Ok(()) => return,
+                        Err(SendTimeoutError::Timeout(op)) => {
+                            self.try_apply_writes();
+                            Err(TrySendError::Full(op))
+                        }
+                        Err(SendTimeoutError::Disconnected(op)) => {
+                            Err(TrySendError::Disconnected(op))
+                        }
+                    }
+                }
+                Backpressure::DrainInline => {
+                    if !self.try_apply_writes() {
+                        thread::yield_now();
+                    }
+                    self.write_op_ch.try_send(op)
+                }
+                Backpressure::Drop => return self.drop_write_op(op),
+            };
+            match result {
+                Ok(()) => return,
+                Err(TrySendError::Full(returned)) => op = returned,
+                Err(TrySendError::Disconnected(_)) => panic!("Failed to schedule a write op"),
+            }
+        }
+    }
+
+    /// Gives up a write op which did not fit in the write buffer. An inserted
+    /// value is removed from the map, unless it has been replaced since, as
+    /// the policy would not know about it.
+    ///
+    /// A removal is never given up, as the policy would keep the entry's
+    /// nodes. They are unlinked right away, or once the writes have been
+    /// applied if this thread is applying them.
+    pub(crate) fn drop_write_op(&self, op: WriteOp<K, V>) {
+        match op {
+            Upsert(key, entry) => {
+                self.inner.stats.record(Counter::DroppedWrite);
+                trace_event!("dropped a write");
+                if let Some(entry) = self
+                    .inner
+                    .cache
+                    .remove_if(&key, |_, current| Arc::ptr_eq(current, &entry))
+                {
+                    self.inner.on_removal(&key, &entry, RemovalCause::Dropped);
+                }
+            }
+            // The deques are locked by this thread while it applies the
+            // writes, and the nodes may be in use.
+            Remove(entry) if self.inner.is_applying_writes() => {
+                self.inner.pending_removals.lock().push(entry);
+            }
+            Remove(entry) => unsafe {
+                self.inner
+                    .deques
+                    .lock()
+                    .unlink_entry(&mut entry.nodes.lock())
+            },
+        }
+    }
+
+    pub(crate) fn backpressure(&self) -> Backpressure {
+        self.inner.backpressure
+    }
+
+    /// Like `schedule_write_op`, but returns the op instead of blocking when
+    /// the channel is full.
+    pub(crate) fn try_schedule_write_op(
+        &self,
+        op: WriteOp<K, V>,
+    ) -> Result<(), TrySendError<WriteOp<K, V>>> {
+        self.write_op_ch.try_send(op)?;
         self.apply_reads_writes_if_needed();
         Ok(())
     }
 
-    fn apply_reads_if_needed(&self) {
-        let len = self.read_op_ch.len();
+    /// Applies the pending writes unless another thread is applying them.
+    /// Returns `false` if the lock was not acquired.
+    pub(crate) fn try_apply_writes(&self) -> bool {
+        match self.inner.writes_apply_lock.try_lock() {
+            Some(w_lock) => {
+                let len = self.write_op_ch.len();
+                self.inner.apply_writes(w_lock, len);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Writes the value of an `insert` or `insert_with_ttl` call to the map
+    /// right away, replacing the existing one if any. The policy admits it
+    /// when the returned write op is applied.
+    pub(crate) fn insert_op(&self, key: K, value: V, ttl: Option<Duration>) -> WriteOp<K, V> {
+        let now = self.inner.time_source.now();
+        let key = Arc::new(key);
+        let value = Arc::new(value);
+        let entry = Arc::new(ValueEntry::new(
+            Arc::clone(&value),
+            EntryTimes::new(now, ttl),
+        ));
+        // Take over the nodes of the entry being replaced, if any.
+        let mut replacing = None;
+        let old = self.inner.cache.insert_or_modify(
+            Arc::clone(&key),
+            Arc::clone(&entry),
+            |_, existing| {
+                let times = EntryTimes::new(now, ttl);
+                let new = Arc::new(ValueEntry::replacing(Arc::clone(&value), times, existing));
+                replacing = Some(Arc::clone(&new));
+                new
+            },
+        );
+
+        match &old {
+            Some(old) if self.inner.is_expired(old, now) => {
+                self.inner.stats.record(Counter::Insert);
+                self.inner.on_removal(&key, old, RemovalCause::Expired);
+            }
+            Some(old) => {
+                self.inner.stats.record(Counter::Update);
+                self.inner.on_removal(&key, old, RemovalCause::Replaced);
+            }
+            None => self.inner.stats.record(Counter::Insert),
+        }
+        match (old, replacing) {
+            (Some(_), Some(new)) => Upsert(key, new),
+            _ => Upsert(key, entry),
+        }
+    }
+
+    /// Applies the reads if the stripe which the current thread has just
+    /// read into holds `len` ops, and that is enough.
+    fn apply_reads_if_needed(&self, len: usize) {
+        // An eviction listener which reads from the cache while the writes
+        // are applied must not take the locks its thread already holds.
+        if self.inner.is_applying_writes() {
+            return;
+        }
 
         if self.should_apply_reads(len) {
             if let Some(lock) = self.inner.reads_apply_lock.try_lock() {
-                self.inner.apply_reads(lock, len);
+                self.inner.apply_reads(lock);
+                // The window is resized along with the writes, as it may
+                // evict, so that a read-only workload adapts it too.
+                if self.inner.climber.lock().has_adjustment() {
+                    self.try_apply_writes();
+                }
             }
         }
     }
 
     fn apply_reads_writes_if_needed(&self) {
+        if self.inner.is_applying_writes() {
+            return;
+        }
         let w_len = self.write_op_ch.len();
 
         if self.should_apply_writes(w_len) {
-            let r_len = self.read_op_ch.len();
             if let Some(r_lock) = self.inner.reads_apply_lock.try_lock() {
-                self.inner.apply_reads(r_lock, r_len);
+                self.inner.apply_reads(r_lock);
             }
 
             if let Some(w_lock) = self.inner.writes_apply_lock.try_lock() {
@@ -130,194 +477,585 @@ where
         }
     }
 
-    fn should_apply_reads(&self, ch_len: usize) -> bool {
-        // TODO: Also check how long past since the last run. (e.g > 100 micro secs)
-        ch_len >= READ_LOG_HIGH_WATER_MARK
+    fn insert_and_schedule(&self, key: Arc<K>, value: V) -> Arc<V> {
+        let (v, op) = self.insert_entry(key, value);
+        if let Some(op) = op {
+            self.schedule_write_op(op);
+        }
+        v
+    }
+
+    fn should_apply_reads(&self, stripe_len: usize) -> bool {
+        stripe_len >= READ_LOG_HIGH_WATER_MARK
+            || (stripe_len > 0 && self.is_maintenance_due(&self.inner.last_reads_applied))
     }
 
     fn should_apply_writes(&self, ch_len: usize) -> bool {
-        // TODO: Also check how long past since the last run. (e.g > 100 micro secs)
         ch_len >= WRITE_LOG_HIGH_WATER_MARK
+            || (ch_len > 0 && self.is_maintenance_due(&self.inner.last_writes_applied))
+    }
+
+    fn is_maintenance_due(&self, last_applied: &AtomicU64) -> bool {
+        let now = self.inner.time_source.now();
+        now.saturating_sub(last_applied.load(Ordering::Relaxed)) >= MAINTENANCE_INTERVAL_NANOS
     }
 }
 
 impl<K, V, S> ConcurrentCache<K, V> for LFUCache<K, V, S>
 where
-    K: Clone + Debug + Eq + Hash,
+    K: Debug + Eq + Hash,
     S: BuildHasher,
 {
     fn get(&self, key: &K) -> Option<Arc<V>> {
-        let v = self.inner.get(key);
-        self.record_read_op(key);
-        v
+        let now = self.inner.time_source.now();
+        match self.inner.get_entry(key) {
+            // An expired entry is treated as missing until the policy
+            // removes it.
+            Some(entry) if !self.inner.is_expired(&entry, now) => {
+                self.inner.stats.record(Counter::Hit);
+                entry.times.set_last_accessed(now);
+                let v = Arc::clone(&entry.value);
+                self.record_read_op(ReadExisting(self.inner.hash(key), entry));
+                Some(v)
+            }
+            _ => {
+                self.inner.stats.record(Counter::Miss);
+                self.record_read_op(ReadMissing(self.inner.hash(key)));
+                None
+            }
+        }
     }
 
-    fn get_or_insert(&self, _key: K, _default: V) -> Arc<V> {
-        unimplemented!() // todo!() was introduced in Rust 1.40.0.
+    fn get_or_insert(&self, key: K, default: V) -> Arc<V> {
+        self.get_or_insert_with(key, || default)
     }
 
-    fn get_or_insert_with<F>(&self, _key: K, _default: F) -> Arc<V>
+    fn get_or_insert_with<F>(&self, key: K, default: F) -> Arc<V>
     where
         F: FnOnce() -> V,
     {
-        unimplemented!()
+        if let Some(v) = self.get(&key) {
+            return v;
+        }
+
+        // Concurrent calls for the same key run only one of the initializers.
+        let key = Arc::new(key);
+        self.inner.value_initializer.init_or_read(
+            &key,
+            || self.get_value(&key),
+            default,
+            |value| self.insert_and_schedule(Arc::clone(&key), value),
+        )
+    }
+
+    fn try_get_or_insert_with<F, E>(&self, key: K, init: F) -> Result<Arc<V>, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+        E: Clone + Send + Sync + 'static,
+    {
+        if let Some(v) = self.get(&key) {
+            return Ok(v);
+        }
+
+        let key = Arc::new(key);
+        self.inner.value_initializer.try_init_or_read(
+            &key,
+            || self.get_value(&key),
+            init,
+            |value| self.insert_and_schedule(Arc::clone(&key), value),
+        )
     }
 
     fn insert(&self, key: K, value: V) {
-        self.schedule_insert_op(key, value)
-            .expect("Failed to insert");
+        self.schedule_write_op(self.insert_op(key, value, None));
+    }
+
+    fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        self.schedule_write_op(self.insert_op(key, value, Some(ttl)));
     }
 
     fn remove(&self, key: &K) -> Option<Arc<V>> {
-        self.schedule_remove_op(key).expect("Failed to remove");
-        self.inner.get(key)
+        let (value, op) = self.remove_entry(key);
+        if let Some(op) = op {
+            self.schedule_write_op(op);
+        }
+        value
     }
 }
 
 unsafe impl<K, V, S> Send for LFUCache<K, V, S> {}
 unsafe impl<K, V, S> Sync for LFUCache<K, V, S> {}
 
+// Lets another thread drop the last reference to the cache, so the keys,
+// values and hasher must be safe to send there.
+struct WeakInner<K, V, S>(Weak<LFUInner<K, V, S>>);
+
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Send + Sync> Send for WeakInner<K, V, S> {}
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Send + Sync> Sync for WeakInner<K, V, S> {}
+
+// Lets the policy hash the keys with the map's hasher.
+struct SharedBuildHasher<S>(Arc<S>);
+
+impl<S: BuildHasher> BuildHasher for SharedBuildHasher<S> {
+    type Hasher = S::Hasher;
+
+    fn build_hasher(&self) -> S::Hasher {
+        self.0.build_hasher()
+    }
+}
+
 struct LFUInner<K, V, S> {
-    capacity: usize,
     cache: Cache<K, V, S>,
-    keys: Mutex<KeySet<K>>,
-    frequency_sketch: RwLock<CountMinSketch8<K>>,
+    build_hasher: Arc<S>,
+    deques: Mutex<Deques<K>>,
+    // Counts the key hashes.
+    frequency_sketch: RwLock<FrequencySketch>,
+    climber: Mutex<HillClimber>,
+    value_initializer: ValueInitializer<K, V>,
+    weigher: Option<Weigher<K, V>>,
+    expiration: Expiration,
+    time_source: TimeSource,
+    notifier: Option<Notifier<K, V>>,
+    stats: StatsCounter,
+    backpressure: Backpressure,
+    // The thread applying the writes, if any. See `thread_id`.
+    writes_applier: AtomicUsize,
+    // Removals made by the thread applying the writes, e.g. from an eviction
+    // listener, which did not fit in the write buffer.
+    pending_removals: Mutex<Vec<Arc<ValueEntry<K, V>>>>,
+    // The times the reads and the writes were last applied.
+    last_reads_applied: AtomicU64,
+    last_writes_applied: AtomicU64,
     reads_apply_lock: Mutex<()>,
     writes_apply_lock: Mutex<()>,
-    read_op_ch: Receiver<K>,
+    read_buffer: ReadBuffer<ReadOp<K, V>>,
     write_op_ch: Receiver<WriteOp<K, V>>,
 }
 
 impl<K, V, S> LFUInner<K, V, S>
 where
-    K: Clone + Debug + Eq + Hash,
+    K: Debug + Eq + Hash,
     S: BuildHasher,
 {
-    fn new(
-        capacity: usize,
-        build_hasher: S,
-        read_op_ch: Receiver<K>,
-        write_op_ch: Receiver<WriteOp<K, V>>,
-    ) -> Self {
-        let skt_capacity = usize::max(capacity, 100);
-        let frequency_sketch = CountMinSketch8::new(skt_capacity, 0.95, 10.0)
-            .expect("Failed to create the frequency sketch");
+    fn new(builder: LFUCacheBuilder<K, V, S>, write_op_ch: Receiver<WriteOp<K, V>>) -> Self {
+        let capacity = builder.capacity;
+        let max_weight = builder.max_weight.unwrap_or(capacity as u64);
+        let mut frequency_sketch = FrequencySketch::new(usize::max(capacity, 100), rand::random());
+        if builder.doorkeeper {
+            frequency_sketch = frequency_sketch.with_doorkeeper();
+        }
+        let climber = HillClimber::new(max_weight, frequency_sketch.sample_size() as u64);
+        let build_hasher = Arc::new(builder.build_hasher);
 
         Self {
-            capacity,
-            cache: cht::HashMap::with_capacity_and_hasher(capacity, build_hasher),
-            keys: Mutex::new(HashSet::default()),
+            cache: cht::HashMap::with_capacity_and_hasher(
+                capacity,
+                SharedBuildHasher(Arc::clone(&build_hasher)),
+            ),
+            build_hasher,
+            deques: Mutex::new(Deques::new(max_weight)),
             frequency_sketch: RwLock::new(frequency_sketch),
+            climber: Mutex::new(climber),
+            value_initializer: ValueInitializer::new(),
+            weigher: builder.weigher,
+            expiration: builder.expiration,
+            time_source: TimeSource::new(builder.clock),
+            notifier: builder.notifier,
+            stats: StatsCounter::new(builder.record_stats),
+            backpressure: builder.backpressure,
+            writes_applier: AtomicUsize::new(0),
+            pending_removals: Mutex::new(Vec::new()),
+            last_reads_applied: AtomicU64::new(0),
+            last_writes_applied: AtomicU64::new(0),
             reads_apply_lock: Mutex::new(()),
             writes_apply_lock: Mutex::new(()),
-            read_op_ch,
+            read_buffer: ReadBuffer::new(),
             write_op_ch,
         }
     }
 
-    fn get(&self, key: &K) -> Option<Arc<V>> {
+    fn hash(&self, key: &K) -> u64 {
+        self.build_hasher.hash_one(key)
+    }
+
+    fn get_entry(&self, key: &K) -> Option<Arc<ValueEntry<K, V>>> {
         self.cache.get(key)
     }
 
-    // fn get_or_insert_with<F>(&mut self, _key: K, _default: F) -> Arc<V>
-    // where
-    //     F: FnOnce() -> V,
-    // {
-    //     unimplemented!()
-    // }
+    fn is_expired(&self, entry: &ValueEntry<K, V>, now: u64) -> bool {
+        self.expiration.is_expired(&entry.times, now)
+    }
 
-    // fn insert(&mut self, key: K, value: V) {
-    //     println!(
-    //         "insert() - estimated frequency of {:?}: {}",
-    //         key,
-    //         self.frequency_sketch.read().estimate(&key)
-    //     );
-    //     self.do_insert(key, Arc::new(value));
-    // }
+    fn on_removal(&self, key: &Arc<K>, entry: &ValueEntry<K, V>, cause: RemovalCause) {
+        self.stats.record_removal(cause);
+        trace_event!(key = ?key, ?cause, "removed an entry");
+        if let Some(notifier) = &self.notifier {
+            notifier.notify(Arc::clone(key), Arc::clone(&entry.value), cause);
+        }
+    }
 
-    fn apply_reads(&self, _lock: MutexGuard<'_, ()>, count: usize) {
+    fn weigh(&self, key: &K, value: &V) -> u64 {
+        self.weigher
+            .as_ref()
+            .map_or(1, |weigher| u64::from(weigher(key, value)))
+    }
+
+    /// Applies the pending reads and writes, and removes the expired
+    /// entries, waiting for any other thread doing so.
+    fn maintain(&self) {
+        let r_lock = self.reads_apply_lock.lock();
+        self.apply_reads(r_lock);
+
+        let w_lock = self.writes_apply_lock.lock();
+        self.apply_writes(w_lock, self.write_op_ch.len());
+    }
+
+    fn apply_reads(&self, _lock: MutexGuard<'_, ()>) {
+        let _span = maintenance_span!("apply_reads", batch = tracing::field::Empty);
+        self.last_reads_applied
+            .store(self.time_source.now(), Ordering::Relaxed);
         let mut freq = self.frequency_sketch.write();
-        let ch = &self.read_op_ch;
-        for _ in 0..count {
-            match ch.try_recv() {
-                Ok(key) => freq.increment(&key),
-                Err(_) => break,
+        let mut deqs = self.deques.lock();
+        let mut climber = self.climber.lock();
+        let _count = self.read_buffer.drain(|op| match op {
+            ReadExisting(hash, entry) => {
+                freq.increment(hash);
+                climber.record(true);
+                // The node is `None` if the key has been evicted after it
+                // was read.
+                if let Some(node) = entry.nodes.lock().access {
+                    unsafe { deqs.on_access(node) };
+                }
             }
-        }
+            ReadMissing(hash) => {
+                freq.increment(hash);
+                climber.record(false);
+            }
+        });
+        record_span_field!(_span, "batch", _count);
+    }
+
+    fn is_applying_writes(&self) -> bool {
+        self.writes_applier.load(Ordering::Relaxed) == thread_id()
     }
 
     fn apply_writes(&self, _lock: MutexGuard<'_, ()>, count: usize) {
+        let _span = maintenance_span!("apply_writes", batch = tracing::field::Empty);
+        let _applier = WritesApplier::enter(&self.writes_applier);
+        self.last_writes_applied
+            .store(self.time_source.now(), Ordering::Relaxed);
         let freq = self.frequency_sketch.read();
-        let mut keys = self.keys.lock();
-
-        let ch = &self.write_op_ch;
-        for _ in 0..count {
-            match ch.try_recv() {
-                Ok(Insert(key, value)) => self.do_insert(key, Arc::new(value), &mut keys, &freq),
-                Ok(Remove(key)) => {
-                    keys.remove(&key);
-                }
-                Err(_) => break,
-            };
+        let mut deqs = self.deques.lock();
+
+        let _applied = self
+            .write_op_ch
+            .try_iter()
+            .take(count)
+            .map(|op| match op {
+                Upsert(key, entry) => self.do_upsert(key, entry, &mut deqs, &freq),
+                Remove(entry) => unsafe { deqs.unlink_entry(&mut entry.nodes.lock()) },
+            })
+            .count();
+        record_span_field!(_span, "batch", _applied);
+
+        let adjustment = self.climber.lock().take_adjustment();
+        if adjustment != 0 {
+            deqs.resize_window(adjustment);
+            self.evict(&mut deqs, &freq);
+        }
+
+        self.remove_expired(&mut deqs);
+
+        // Evicting is over, so the nodes of the removals made meanwhile are
+        // no longer in use.
+        for entry in self.pending_removals.lock().drain(..) {
+            unsafe { deqs.unlink_entry(&mut entry.nodes.lock()) };
         }
     }
 
-    fn admit(&self, candidate: &K, victim: &K, freq: &CountMinSketch8<K>) -> bool {
-        // TODO: Implement some randomness to mitigate hash DoS.
-        freq.estimate(candidate) > freq.estimate(victim)
+    fn do_upsert(
+        &self,
+        key: Arc<K>,
+        entry: Arc<ValueEntry<K, V>>,
+        deqs: &mut Deques<K>,
+        freq: &FrequencySketch,
+    ) {
+        // Skip the entry if the key has been evicted since it was written to
+        // the map. If the entry has been replaced, update the nodes with the
+        // current one.
+        if let Some(current) = self.owner_entry(&key, &entry.nodes) {
+            self.on_write(&key, &current, deqs, freq);
+        }
     }
 
-    fn do_insert(&self, key: K, value: Arc<V>, keys: &mut KeySet<K>, freq: &CountMinSketch8<K>) {
-        if self.cache.len() < self.capacity {
-            let key = Arc::new(key);
-            keys.insert(Arc::clone(&key));
-            self.cache.insert(key, value);
-        } else {
-            let victim = self.find_cache_victim(keys, freq);
-            if self.admit(&key, &victim, freq) {
-                let key = Arc::new(key);
-                {
-                    keys.remove(&victim);
-                    keys.insert(Arc::clone(&key));
-                }
-                self.cache.remove(&victim);
-                self.cache.insert(key, value);
+    /// Updates the position of the entry in the access order, and in the
+    /// write order or the timer wheel.
+    fn on_write(
+        &self,
+        key: &Arc<K>,
+        entry: &ValueEntry<K, V>,
+        deqs: &mut Deques<K>,
+        freq: &FrequencySketch,
+    ) {
+        let weight = self.weigh(key, &entry.value);
+        let mut nodes = entry.nodes.lock();
+        match nodes.access {
+            Some(node) => unsafe {
+                deqs.set_weight(node, weight);
+                deqs.on_access(node);
+            },
+            None => {
+                let owner = Arc::clone(&entry.nodes);
+                let hash = self.hash(key);
+                nodes.access = Some(deqs.push_window(Arc::clone(key), hash, weight, owner));
             }
         }
+
+        unsafe { deqs.unlink_expiry(&mut nodes) };
+        let owner = NodeOwner {
+            key: Arc::clone(key),
+            nodes: Arc::clone(&entry.nodes),
+        };
+        match entry.times.expiration_time() {
+            Some(time) => nodes.expiry = Some(deqs.schedule_expiry(owner, time)),
+            None if self.expiration.time_to_live().is_some() => {
+                nodes.write = Some(deqs.push_write_order(owner))
+            }
+            None => (),
+        }
+        drop(nodes);
+
+        self.evict(deqs, freq);
     }
 
-    // TODO: Run this periodically in background.
-    fn find_cache_victim(&self, keys: &mut KeySet<K>, freq: &CountMinSketch8<K>) -> Arc<K> {
-        let mut victim = None;
+    /// Moves the entries overflowing the window to the main space. When the
+    /// main space is full, each of them competes with the main space's
+    /// victims it would replace, and only the side with the higher estimated
+    /// frequency stays.
+    fn evict(&self, deqs: &mut Deques<K>, freq: &FrequencySketch) {
+        let _span = maintenance_span!("evict");
+        while deqs.is_window_overflowed() {
+            let candidate = deqs.window_front().expect("The window is empty");
+            let weight = unsafe { candidate.as_ref().element().weight };
+            if deqs.main_weight() + weight <= deqs.main_capacity() {
+                unsafe { deqs.move_to_probation(candidate) };
+                continue;
+            }
 
-        for key in keys.iter() {
-            let freq0 = freq.estimate(key);
+            match self.find_victims(candidate, weight, deqs, freq) {
+                Some(victims) => {
+                    for victim in victims {
+                        self.evict_node(victim, deqs, RemovalCause::Size);
+                    }
+                    unsafe { deqs.move_to_probation(candidate) };
+                }
+                None => {
+                    self.stats.record(Counter::Rejection);
+                    self.evict_node(candidate, deqs, RemovalCause::Rejected)
+                }
+            }
+        }
+
+        // An entry of the main space may have become heavier when its value
+        // was replaced.
+        while deqs.main_weight() > deqs.main_capacity() {
+            let victim = deqs.main_victims().next();
             match victim {
-                None => victim = Some((freq0, key)),
-                Some((freq1, _)) if freq0 < freq1 => victim = Some((freq0, key)),
-                Some(_) => (),
+                Some(victim) => self.evict_node(victim, deqs, RemovalCause::Size),
+                None => break,
+            }
+        }
+    }
+
+    /// Returns the main space entries to evict to make room for the
+    /// candidate, or `None` if it is rejected. The candidate is admitted if
+    /// its estimated frequency is higher than the combined frequency of the
+    /// victims.
+    fn find_victims(
+        &self,
+        candidate: DeqNodePtr<K>,
+        weight: u64,
+        deqs: &Deques<K>,
+        freq: &FrequencySketch,
+    ) -> Option<Vec<DeqNodePtr<K>>> {
+        if weight > deqs.main_capacity() {
+            return None;
+        }
+
+        let mut excess = deqs.main_weight() + weight - deqs.main_capacity();
+        let mut victims = Vec::new();
+        let mut victims_frequency = 0;
+        for victim in deqs.main_victims() {
+            if excess == 0 {
+                break;
+            }
+            let victim_node = unsafe { victim.as_ref().element() };
+            victims_frequency += u64::from(freq.frequency(victim_node.hash));
+            excess = excess.saturating_sub(victim_node.weight);
+            victims.push(victim);
+        }
+
+        let c_hash = unsafe { candidate.as_ref().element().hash };
+        if excess == 0 && admit(u64::from(freq.frequency(c_hash)), victims_frequency) {
+            Some(victims)
+        } else {
+            None
+        }
+    }
+
+    /// Removes the entries which have expired. The write and access orders
+    /// only need to be checked from their fronts, and the timer wheel yields
+    /// the entries with their own time-to-live once they are due.
+    fn remove_expired(&self, deqs: &mut Deques<K>) {
+        let _span = maintenance_span!("remove_expired");
+        let now = self.time_source.now();
+
+        if self.expiration.time_to_live().is_some() {
+            while let Some(node) = deqs.write_order_front() {
+                let NodeOwner { key, nodes } = unsafe { node.as_ref().element() }.clone();
+                if !self.evict_if_expired(&key, &nodes, deqs, now) {
+                    break;
+                }
+            }
+        }
+
+        if self.expiration.time_to_idle().is_some() {
+            let regions = [
+                CacheRegion::Window,
+                CacheRegion::MainProbation,
+                CacheRegion::MainProtected,
+            ];
+            for region in regions.iter() {
+                while let Some(node) = deqs.front_of(*region) {
+                    let element = unsafe { node.as_ref().element() };
+                    let (key, nodes) = (Arc::clone(&element.key), Arc::clone(&element.owner));
+                    if !self.evict_if_expired(&key, &nodes, deqs, now) {
+                        break;
+                    }
+                }
             }
         }
-        // TODO: Remove clone().
-        // Maybe the cache map should have <Arc<K>, Arc<V>> instead of <K, Arc<V>>?
-        let (_, k) = victim.expect("No victim found");
-        Arc::clone(k)
+
+        deqs.advance_timers(now);
+        while let Some(node) = deqs.due_timer() {
+            let NodeOwner { key, nodes } = unsafe { node.as_ref().element() }.item.clone();
+            match self.owner_entry(&key, &nodes) {
+                // The entry has been replaced by one which expires later, and
+                // its write op is yet to be applied.
+                Some(entry) if !self.is_expired(&entry, now) => unsafe {
+                    let time = entry.times.expiration_time();
+                    deqs.reschedule_expiry(&mut nodes.lock(), time);
+                },
+                _ => self.evict_entry(&key, &nodes, deqs, RemovalCause::Expired),
+            }
+        }
+    }
+
+    /// Evicts the entry which owns the nodes if it has expired. The nodes of
+    /// an entry which is no longer in the map are unlinked as well. Returns
+    /// `false` if the entry is live.
+    fn evict_if_expired(
+        &self,
+        key: &Arc<K>,
+        nodes: &SharedEntryNodes<K>,
+        deqs: &mut Deques<K>,
+        now: u64,
+    ) -> bool {
+        match self.owner_entry(key, nodes) {
+            Some(entry) if !self.is_expired(&entry, now) => false,
+            _ => {
+                self.evict_entry(key, nodes, deqs, RemovalCause::Expired);
+                true
+            }
+        }
+    }
+
+    /// The entry in the map which owns the nodes. There is none if the entry
+    /// has been removed, or its write op dropped.
+    fn owner_entry(&self, key: &K, nodes: &SharedEntryNodes<K>) -> Option<Arc<ValueEntry<K, V>>> {
+        self.cache
+            .get(key)
+            .filter(|entry| Arc::ptr_eq(&entry.nodes, nodes))
+    }
+
+    /// Evicts the entry which owns the node. If the entry has already been
+    /// removed from the map, only its nodes are unlinked.
+    fn evict_node(&self, node: DeqNodePtr<K>, deqs: &mut Deques<K>, cause: RemovalCause) {
+        let element = unsafe { node.as_ref().element() };
+        let (key, owner) = (Arc::clone(&element.key), Arc::clone(&element.owner));
+        self.evict_entry(&key, &owner, deqs, cause);
+    }
+
+    fn evict_entry(
+        &self,
+        key: &Arc<K>,
+        nodes: &SharedEntryNodes<K>,
+        deqs: &mut Deques<K>,
+        cause: RemovalCause,
+    ) {
+        unsafe { deqs.unlink_entry(&mut nodes.lock()) };
+        // A newer entry for the key may have been written to the map. Keep it
+        // if it does not share the evicted nodes.
+        let removed = self
+            .cache
+            .remove_if(key, |_, current| Arc::ptr_eq(&current.nodes, nodes));
+        if let Some(removed) = removed {
+            self.on_removal(key, &removed, cause);
+        }
+    }
+}
+
+/// A non-zero number identifying the current thread.
+fn thread_id() -> usize {
+    thread_local!(static ID: u8 = const { 0 });
+    ID.with(|id| id as *const u8 as usize)
+}
+
+/// Marks the current thread as the one applying the writes, until dropped.
+/// It is cleared even if the weigher or a listener panics, so that the
+/// thread's later writes are not taken for re-entrant ones.
+struct WritesApplier<'a>(&'a AtomicUsize);
+
+impl<'a> WritesApplier<'a> {
+    fn enter(applier: &'a AtomicUsize) -> Self {
+        applier.store(thread_id(), Ordering::Relaxed);
+        Self(applier)
+    }
+}
+
+impl Drop for WritesApplier<'_> {
+    fn drop(&mut self) {
+        self.0.store(0, Ordering::Relaxed);
     }
 }
 
-// To see the debug prints, run test as `cargo test -- --nocapture`
 #[cfg(test)]
 mod tests {
-    use super::{ConcurrentCache, LFUCache};
+    use super::{Backpressure, ConcurrentCache, FrequencySketch, LFUCache};
+    use crate::clock::MockClock;
+    use crate::notification::RemovalCause;
+    use crate::read_buffer::STRIPE_CAPACITY;
+    use parking_lot::Mutex;
+    use std::collections::hash_map::DefaultHasher;
     use std::hash::BuildHasherDefault;
-    use std::sync::Arc;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
 
     #[test]
-    fn naive_basics() {
-        let cache = LFUCache::new(3);
+    fn w_tinylfu_basics() {
+        // window: 1, probation + protected: 2. A fixed hasher and sketch
+        // seed keep the frequencies the same from run to run.
+        let cache = LFUCache::builder(3)
+            .hasher(BuildHasherDefault::<DefaultHasher>::default())
+            .build();
+        *cache.inner.frequency_sketch.write() = FrequencySketch::new(100, 0);
         cache.insert("a", "alice");
         cache.insert("b", "bob");
         cache.sync();
+        // window: [b], probation: [a]
 
         assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
         assert_eq!(cache.get(&"b"), Some(Arc::new("bob")));
@@ -327,26 +1065,739 @@ mod tests {
 
         cache.insert("c", "cindy");
         cache.sync();
+        // window: [c], probation: [b], protected: [a]
 
         assert_eq!(cache.get(&"c"), Some(Arc::new("cindy")));
         // counts: a -> 2, b -> 2, c -> 1
 
-        // "d" should not be admitted because its frequency is too low.
-        cache.insert("d", "david"); //        count: d -> 0
+        // "c" is pushed out of the window by "d". It should not be admitted
+        // to the main space because its frequency is lower than b's.
+        cache.insert("d", "david");
+        cache.sync();
+        assert_eq!(cache.get(&"c"), None);
+        assert_eq!(cache.get(&"d"), Some(Arc::new("david")));
+        assert_eq!(cache.get(&"e"), None);
+        assert_eq!(cache.get(&"e"), None);
+        assert_eq!(cache.get(&"e"), None);
+        // counts: a -> 2, b -> 2, c -> 2, d -> 1, e -> 3
+
+        // "d" is pushed out of the window by "e" and rejected.
+        cache.insert("e", "emily");
         cache.sync();
-        assert_eq!(cache.get(&"d"), None); //        d -> 1
+        assert_eq!(cache.get(&"d"), None);
 
-        cache.insert("d", "david");
+        // "e" should be admitted and "b" should be evicted
+        // because e's frequency is higher then b's.
+        cache.insert("f", "frank");
+        cache.sync();
+        assert_eq!(cache.get(&"e"), Some(Arc::new("emily")));
+        assert_eq!(cache.get(&"f"), Some(Arc::new("frank")));
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
+
+        assert_eq!(cache.remove(&"a"), Some(Arc::new("alice")));
+    }
+
+    #[test]
+    fn weighted_capacity() {
+        // window: 1, probation + protected: 99
+        let cache = LFUCache::builder(10)
+            .max_weight(100)
+            .weigher(|_, v: &Vec<u8>| v.len() as u32)
+            .build();
+        cache.insert("a", vec![0; 30]);
+        cache.insert("b", vec![0; 30]);
+        cache.sync();
+        for _ in 0..3 {
+            cache.get(&"a");
+            cache.get(&"b");
+        }
+        cache.sync();
+
+        cache.insert("huge", vec![0; 120]);
+        cache.sync();
+        assert_eq!(cache.get(&"huge"), None);
+
+        // "c" needs the room of "a", and is rejected until its frequency is
+        // higher than a's.
+        cache.insert("c", vec![0; 60]);
+        cache.sync();
+        assert_eq!(cache.get(&"c"), None);
+        for _ in 0..10 {
+            cache.get(&"c");
+        }
+        cache.insert("c", vec![0; 60]);
+        cache.sync();
+        assert_eq!(cache.get(&"c"), Some(Arc::new(vec![0; 60])));
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"b"), Some(Arc::new(vec![0; 30])));
+        assert_eq!(cache.inner.deques.lock().main_weight(), 90);
+    }
+
+    #[test]
+    fn huge_max_weight() {
+        let cache = LFUCache::builder(10)
+            .max_weight(u64::MAX)
+            .weigher(|_, _: &u32| u32::MAX)
+            .build();
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        cache.sync();
+        // Enough reads for the window to be resized by huge steps.
+        for _ in 0..1_000 {
+            for i in 0..20 {
+                cache.get(&i);
+            }
+            cache.sync();
+        }
+        for i in 0..10 {
+            assert_eq!(cache.get(&i), Some(Arc::new(i)));
+        }
+    }
+
+    #[test]
+    fn time_to_live() {
+        let clock = MockClock::new();
+        let cache = LFUCache::builder(100)
+            .time_to_live(Duration::from_secs(10))
+            .clock(clock.clone())
+            .build();
+        cache.insert("a", "alice");
+        cache.insert_with_ttl("b", "bob", Duration::from_secs(60));
+        cache.sync();
+        assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"b"), Some(Arc::new("bob")));
+        // An expired entry is replaced by `get_or_insert_with`.
+        assert_eq!(cache.get_or_insert("a", "anna"), Arc::new("anna"));
         cache.sync();
-        assert_eq!(cache.get(&"d"), None); //        d -> 2
 
-        // "d" should be admitted and "c" should be evicted
-        // because d's frequency is higher then c's.
-        cache.insert("d", "dennis");
+        cache.insert_with_ttl("c", "cindy", Duration::from_secs(5));
         cache.sync();
-        assert_eq!(cache.get(&"d"), Some(Arc::new("dennis")));
+        clock.advance(Duration::from_secs(10));
         assert_eq!(cache.get(&"c"), None);
+        cache.sync();
+        assert!(cache.inner.cache.get(&"a").is_none());
+        assert!(cache.inner.cache.get(&"c").is_none());
+        assert_eq!(cache.inner.cache.len(), 1);
+
+        // The timer wheel removes "b" once its own time-to-live has passed.
+        clock.advance(Duration::from_secs(45));
+        cache.sync();
+        assert!(cache.inner.cache.is_empty());
+    }
+
+    #[test]
+    fn time_to_idle() {
+        let clock = MockClock::new();
+        let cache = LFUCache::builder(100)
+            .time_to_idle(Duration::from_secs(10))
+            .clock(clock.clone())
+            .build();
+        cache.insert("a", "alice");
+        cache.insert("b", "bob");
+        cache.sync();
+
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
+        assert_eq!(cache.get(&"b"), None);
+
+        cache.sync();
+        assert!(cache.inner.cache.get(&"b").is_none());
+        assert_eq!(cache.inner.cache.len(), 1);
+    }
+
+    #[test]
+    fn eviction_listener() {
+        let clock = MockClock::new();
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&removed);
+        // window: 1, probation + protected: 2
+        let cache = LFUCache::builder(3)
+            .time_to_live(Duration::from_secs(10))
+            .clock(clock.clone())
+            .eviction_listener(move |k, v, cause| log.lock().push((*k, *v, cause)))
+            .build();
+        cache.insert("a", "alice");
+        cache.insert("b", "bob");
+        cache.insert("a", "anna");
+        cache.insert("c", "cindy");
+        cache.sync();
+        assert_eq!(
+            removed.lock().drain(..).collect::<Vec<_>>(),
+            vec![("a", "alice", RemovalCause::Replaced)]
+        );
+
+        // "c" is pushed out of the window by "d" and rejected, and "d" is
+        // admitted in place of a less frequent key when "e" is inserted.
+        for _ in 0..3 {
+            cache.get(&"d");
+        }
+        cache.insert("d", "david");
+        cache.insert("e", "emily");
+        cache.sync();
+        let mut causes = removed.lock().drain(..).collect::<Vec<_>>();
+        assert_eq!(causes[0], ("c", "cindy", RemovalCause::Rejected));
+        assert_eq!(
+            causes.pop().map(|(_, _, cause)| cause),
+            Some(RemovalCause::Size)
+        );
+
+        cache.remove(&"d");
+        cache.sync();
+        assert_eq!(
+            removed.lock().drain(..).collect::<Vec<_>>(),
+            vec![("d", "david", RemovalCause::Explicit)]
+        );
+
+        clock.advance(Duration::from_secs(10));
+        cache.sync();
+        let removed = removed.lock();
+        assert!(removed.len() >= 2);
+        assert!(removed
+            .iter()
+            .all(|(_, _, cause)| *cause == RemovalCause::Expired));
+    }
+
+    #[test]
+    fn queued_eviction_listener() {
+        let (snd, rcv) = crossbeam_channel::unbounded();
+        let cache = LFUCache::builder(3)
+            .queued_eviction_listener(move |k, v, cause| {
+                // A slow listener does not hold up the maintenance.
+                thread::sleep(Duration::from_millis(10));
+                snd.send((*k, *v, cause)).expect("Failed to send");
+            })
+            .build();
+        cache.insert("a", "alice");
+        cache.insert("a", "anna");
+        cache.sync();
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            rcv.recv_timeout(timeout),
+            Ok(("a", "alice", RemovalCause::Replaced))
+        );
+    }
+
+    #[test]
+    fn stats() {
+        // window: 1, probation + protected: 2
+        let cache = LFUCache::builder(3).record_stats().build();
+        cache.insert("a", "alice");
+        cache.insert("b", "bob");
+        cache.sync();
+        cache.get(&"a");
+        cache.get(&"z");
+        cache.insert("a", "anna");
+        cache.insert("c", "cindy");
+        // "c" is pushed out of the window by "d" and rejected.
+        cache.insert("d", "david");
+        cache.sync();
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!((stats.inserts, stats.updates), (4, 1));
+        assert_eq!(stats.rejections, 1);
+        assert_eq!(stats.removals(RemovalCause::Replaced), 1);
+        assert_eq!(stats.removals(RemovalCause::Rejected), 1);
+        assert_eq!(stats.evictions(), 1);
+
+        // Reads are dropped while the thread's stripe of the read buffer is
+        // full and another thread is applying the reads.
+        let lock = cache.inner.reads_apply_lock.lock();
+        for _ in 0..100 {
+            cache.get(&"a");
+        }
+        drop(lock);
+        assert_eq!(cache.stats().dropped_reads, 100 - STRIPE_CAPACITY as u64);
+    }
+
+    #[test]
+    fn dropped_writes() {
+        let clock = MockClock::new();
+        let cache = LFUCache::builder(1_000)
+            .clock(clock)
+            .backpressure(Backpressure::Drop)
+            .record_stats()
+            .build();
+        // Writes are dropped while the write buffer is full and another
+        // thread is applying it.
+        let lock = cache.inner.writes_apply_lock.lock();
+        for i in 0..300 {
+            cache.insert(i, i);
+        }
+        drop(lock);
+        let dropped = 300 - super::WRITE_LOG_SIZE;
+        let stats = cache.stats();
+        assert_eq!(stats.dropped_writes, dropped as u64);
+        assert_eq!(stats.removals(RemovalCause::Dropped), dropped as u64);
+        assert_eq!(stats.removals(RemovalCause::Rejected), 0);
+        // The dropped values are not left in the map unknown to the policy.
+        assert_eq!(cache.get(&299), None);
+        assert_eq!(cache.inner.cache.len(), super::WRITE_LOG_SIZE);
+        cache.sync();
+        assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    }
+
+    #[test]
+    fn removals_are_not_dropped() {
+        let clock = MockClock::new();
+        let cache = LFUCache::builder(1_000)
+            .clock(clock)
+            .backpressure(Backpressure::Drop)
+            .build();
+        for i in 0..100 {
+            cache.insert(i, i);
+        }
+        cache.sync();
+
+        // The removals do not fit in the write buffer, which is full while
+        // another thread is applying it.
+        let lock = cache.inner.writes_apply_lock.lock();
+        for i in 100..100 + super::WRITE_LOG_SIZE as u32 {
+            cache.insert(i, i);
+        }
+        for i in 0..50 {
+            assert_eq!(cache.remove(&i), Some(Arc::new(i)));
+        }
+        drop(lock);
+        cache.sync();
+
+        let len = cache.inner.cache.len();
+        assert_eq!(len, 50 + super::WRITE_LOG_SIZE);
+        let deqs = cache.inner.deques.lock();
+        assert_eq!(deqs.len(), len);
+        assert_eq!(deqs.total_weight(), len as u64);
+    }
+
+    #[test]
+    fn listener_removals() {
+        // The listener fills the write buffer and removes entries while the
+        // writes are applied.
+        let handle = Arc::new(Mutex::new(None::<LFUCache<u32, u32, _>>));
+        let listener_handle = Arc::clone(&handle);
+        let cache = LFUCache::builder(10)
+            .eviction_listener(move |_, _, _| {
+                let cache = listener_handle.lock().take();
+                if let Some(cache) = cache {
+                    for i in 1_000..1_000 + super::WRITE_LOG_SIZE as u32 {
+                        cache.insert(i, i);
+                    }
+                    for i in 0..5 {
+                        cache.remove(&i);
+                    }
+                }
+            })
+            .build();
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        cache.sync();
+        *handle.lock() = Some(cache.clone());
+        cache.insert(10, 10);
+        cache.sync();
+        assert!(handle.lock().is_none());
+        cache.sync();
+
+        let len = cache.inner.cache.len();
+        assert!(len <= 10);
+        let deqs = cache.inner.deques.lock();
+        assert_eq!(deqs.len(), len);
+        assert_eq!(deqs.total_weight(), len as u64);
+    }
+
+    #[test]
+    fn listener_panics() {
+        // window: 1, main: 0
+        let cache = LFUCache::builder(1)
+            .eviction_listener(|_, _, _| panic!("Listener failure"))
+            .build();
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cache.sync()));
+        assert!(result.is_err());
+
+        // The thread's writes are not taken for a listener's.
+        assert!(!cache.inner.is_applying_writes());
+    }
+
+    #[test]
+    fn backpressure_stress() {
+        for &backpressure in &[
+            Backpressure::WaitThenDrain,
+            Backpressure::DrainInline,
+            Backpressure::Drop,
+        ] {
+            // The listener writes to the cache while the writes are applied,
+            // possibly into a full write buffer.
+            let handle = Arc::new(Mutex::new(None::<LFUCache<u32, u32, _>>));
+            let listener_handle = Arc::clone(&handle);
+            let cache = LFUCache::builder(4)
+                .backpressure(backpressure)
+                .eviction_listener(move |k: Arc<u32>, v, _| {
+                    let cache = listener_handle.lock().clone();
+                    if let (Some(cache), true) = (cache, *k < 1_000) {
+                        cache.insert(*k + 1_000, *v);
+                    }
+                })
+                .build();
+            *handle.lock() = Some(cache.clone());
+
+            let threads = (0..8)
+                .map(|t| {
+                    let cache = cache.clone();
+                    thread::spawn(move || {
+                        for i in 0..2_000 {
+                            cache.insert((t * 2_000 + i) % 1_000, i);
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().expect("Failed to join");
+            }
+
+            while !cache.write_op_ch.is_empty() {
+                cache.sync();
+            }
+            assert!(cache.inner.cache.len() <= 4, "{:?}", backpressure);
+            // Break the cycle between the cache and its listener.
+            handle.lock().take();
+        }
+    }
+
+    #[test]
+    fn remove() {
+        // The clock does not move, so that the write ops are only applied by
+        // `sync`.
+        let clock = MockClock::new();
+        // window: 1, probation + protected: 2
+        let cache = LFUCache::builder(3).clock(clock).build();
+        cache.insert("a", "alice");
+        cache.insert("b", "bob");
+        cache.sync();
+        // window: [b], probation: [a]
+
+        // "c" is admitted in place of "a", which the policy still sees in
+        // probation because its Remove op is applied after the inserts.
+        cache.get(&"c");
+        cache.get(&"c");
+        cache.insert("c", "cindy");
+        cache.insert("d", "david");
+        assert_eq!(cache.remove(&"a"), Some(Arc::new("alice")));
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.remove(&"a"), None);
+        cache.sync();
+        assert_eq!(cache.get(&"b"), Some(Arc::new("bob")));
+        assert_eq!(cache.get(&"c"), Some(Arc::new("cindy")));
+        assert_eq!(cache.inner.cache.len(), 3);
+        assert_eq!(cache.inner.deques.lock().main_weight(), 2);
+
+        assert_eq!(cache.remove(&"d"), Some(Arc::new("david")));
+        cache.sync();
+        assert_eq!(cache.get(&"d"), None);
+        assert_eq!(cache.inner.cache.len(), 2);
+        assert_eq!(cache.inner.deques.lock().main_weight(), 2);
+    }
+
+    #[test]
+    fn stale_frequency_ages() {
+        // window: 1, probation + protected: 2
+        let cache = LFUCache::new(3);
+        cache.insert(0, 0);
+        cache.sync();
+        for _ in 0..15 {
+            cache.get(&0);
+        }
+        // The sketch of a cache of 3 entries halves its counters every
+        // 1,000 samples.
+        for key in 1_000..4_000 {
+            cache.get(&key);
+        }
+        for _ in 0..8 {
+            cache.get(&2);
+        }
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        cache.sync();
+
+        // 2 is admitted in place of 0 at the front of probation, which used
+        // to be hotter.
+        assert_eq!(cache.get(&2), Some(Arc::new(2)));
+        assert_eq!(cache.get(&0), None);
+    }
+
+    #[test]
+    fn hash_flooding() {
+        // window: 1, probation + protected: 2
+        let cache = LFUCache::new(3);
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+        cache.sync();
+        cache.insert(2, 2);
+        cache.sync();
+        // window: [2], probation: [0, 1]
+
+        // The attacker keeps the counters of 0 saturated, as keys sharing
+        // them would, so that warm candidates would never be admitted in
+        // place of it.
+        let victim = cache.inner.hash(&0);
+        let mut rounds = 0;
+        while cache.inner.get_entry(&0).is_some() {
+            assert!(rounds < 5_000, "The victim is pinned");
+            for _ in 0..15 {
+                cache.inner.frequency_sketch.write().increment(victim);
+            }
+            let candidate = 1_000 + rounds;
+            for _ in 0..7 {
+                cache.get(&candidate);
+            }
+            cache.insert(candidate, candidate);
+            cache.sync();
+            rounds += 1;
+        }
+    }
+
+    #[test]
+    fn doorkeeper() {
+        // window: 1, probation + protected: 2. A fixed hasher and sketch
+        // seed keep the doorkeeper's false positives the same from run to
+        // run.
+        let cache = LFUCache::builder(3)
+            .hasher(BuildHasherDefault::<DefaultHasher>::default())
+            .doorkeeper()
+            .build();
+        *cache.inner.frequency_sketch.write() = FrequencySketch::new(100, 0).with_doorkeeper();
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+        cache.sync();
+        // window: [1], probation: [0]
+
+        // One-hit wonders do not reach the counters.
+        for key in 1_000..1_500 {
+            cache.get(&key);
+        }
+        cache.get(&2);
+        cache.get(&2);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        cache.sync();
+        {
+            let freq = cache.inner.frequency_sketch.read();
+            assert_eq!(freq.frequency(cache.inner.hash(&2)), 2);
+            assert_eq!(freq.frequency(cache.inner.hash(&1_000)), 1);
+        }
+        assert_eq!(cache.get(&2), Some(Arc::new(2)));
+        assert_eq!(cache.get(&0), None);
+    }
+
+    #[test]
+    fn adaptive_window() {
+        // The hit rate is sampled every 1,000 reads, and the window is
+        // resized by steps of 6.
+        let cache = LFUCache::new(100);
+        cache.insert(0, 0);
+        cache.sync();
+        assert_eq!(cache.window_capacity(), 1);
+
+        // The hit rate went up from nothing, so the window keeps shrinking,
+        // but it cannot.
+        for _ in 0..1_000 {
+            cache.get(&0);
+        }
+        cache.sync();
+        assert_eq!(cache.window_capacity(), 1);
+
+        // The hit rate fell, so the window grows.
+        for key in 1_000..2_000 {
+            cache.get(&key);
+        }
+        cache.sync();
+        assert_eq!(cache.window_capacity(), 7);
+        assert_eq!(cache.inner.deques.lock().main_capacity(), 93);
+    }
+
+    #[test]
+    fn read_only_adaptive_window() {
+        let cache = LFUCache::new(100);
+        cache.insert(0, 0);
+        cache.sync();
+
+        // As in `adaptive_window`, but the window is resized as the reads are
+        // applied, without any write or `sync`.
+        for _ in 0..1_000 {
+            cache.get(&0);
+        }
+        for key in 1_000..2_500 {
+            cache.get(&key);
+        }
+        assert_eq!(cache.window_capacity(), 7);
+    }
+
+    #[test]
+    fn small_adaptive_window() {
+        // The steps are smaller than an entry, and add up over the samples.
+        let cache = LFUCache::new(10);
+        cache.insert(0, 0);
+        cache.sync();
+        for _ in 0..1_000 {
+            cache.get(&0);
+        }
+        for key in 1_000..4_000 {
+            cache.get(&key);
+        }
+        cache.sync();
+        assert_eq!(cache.window_capacity(), 2);
+    }
+
+    #[test]
+    fn key_without_clone() {
+        #[derive(Debug, PartialEq, Eq, Hash)]
+        struct Key(String);
+
+        // window: 1, probation + protected: 2
+        let cache = LFUCache::new(3);
+        for name in &["a", "b", "c", "d"] {
+            cache.get(&Key(name.to_string()));
+            cache.insert(Key(name.to_string()), *name);
+        }
+        cache.sync();
+        assert_eq!(cache.inner.cache.len(), 3);
+        assert_eq!(cache.remove(&Key("d".to_string())), Some(Arc::new("d")));
+        cache.sync();
+        assert_eq!(cache.inner.cache.len(), 2);
+    }
+
+    #[test]
+    fn read_your_writes() {
+        let clock = MockClock::new();
+        let cache = LFUCache::builder(3).clock(clock).build();
+        cache.insert("a", "alice");
+        assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
+        cache.insert("a", "anna");
+        assert_eq!(cache.get(&"a"), Some(Arc::new("anna")));
+        // The policy has yet to admit the entry.
+        assert_eq!(cache.write_op_ch.len(), 2);
+
+        cache.sync();
+        assert_eq!(cache.get(&"a"), Some(Arc::new("anna")));
+        assert!(cache.inner.deques.lock().window_front().is_some());
+    }
+
+    #[test]
+    fn maintenance_interval() {
+        let clock = MockClock::new();
+        let cache = LFUCache::builder(100).clock(clock.clone()).build();
+        cache.insert("a", "alice");
+        clock.advance(Duration::from_micros(50));
+        cache.insert("b", "bob");
+        // Neither the high water mark nor the interval has been reached.
+        assert_eq!(cache.write_op_ch.len(), 2);
+
+        clock.advance(Duration::from_micros(50));
+        cache.insert("c", "cindy");
+        assert_eq!(cache.write_op_ch.len(), 0);
+    }
+
+    #[test]
+    fn scan_resistance() {
+        let cache = LFUCache::new(100);
+        for _ in 0..3 {
+            for i in 0..10 {
+                cache.insert(i, i);
+                cache.sync();
+                cache.get(&i);
+            }
+        }
+        cache.sync();
+
+        // A scan of one-hit keys goes through the window without evicting
+        // the frequently used keys from the main space.
+        for i in 1000..2000 {
+            cache.insert(i, i);
+            cache.sync();
+        }
+        for i in 0..10 {
+            assert_eq!(cache.get(&i), Some(Arc::new(i)));
+        }
+    }
+
+    #[test]
+    fn get_or_insert_with() {
+        let cache = LFUCache::new(3);
+        // The value is visible before the write op is applied.
+        assert_eq!(cache.get_or_insert_with("a", || "alice"), Arc::new("alice"));
+        assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
+        assert_eq!(cache.get_or_insert_with("a", || "anna"), Arc::new("alice"));
+        assert_eq!(cache.get_or_insert("b", "bob"), Arc::new("bob"));
+        assert_eq!(cache.get_or_insert("b", "bill"), Arc::new("bob"));
+
+        cache.sync();
+        assert_eq!(cache.get(&"a"), Some(Arc::new("alice")));
+        assert_eq!(cache.get(&"b"), Some(Arc::new("bob")));
+        assert_eq!(cache.inner.deques.lock().main_weight(), 1);
+
+        // The entry is replaced by a later insert.
+        cache.insert("a", "anna");
+        cache.sync();
+        assert_eq!(cache.get(&"a"), Some(Arc::new("anna")));
+        assert!(cache.inner.deques.lock().window_front().is_some());
+    }
+
+    #[test]
+    fn single_flight_get_or_insert_with() {
+        let cache = LFUCache::new(100);
+        let num_inits = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let handles = (0..8)
+            .map(|i| {
+                let (cache, num_inits, barrier) =
+                    (cache.clone(), Arc::clone(&num_inits), Arc::clone(&barrier));
+                thread::spawn(move || {
+                    barrier.wait();
+                    cache.get_or_insert_with("key", || {
+                        num_inits.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(50));
+                        i
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let values = handles
+            .into_iter()
+            .map(|h| h.join().expect("Failed to join"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(num_inits.load(Ordering::SeqCst), 1);
+        assert!(values.iter().all(|v| Arc::ptr_eq(v, &values[0])));
+        assert_eq!(cache.get(&"key"), Some(Arc::clone(&values[0])));
+    }
+
+    #[test]
+    fn try_get_or_insert_with() {
+        let cache = LFUCache::new(3);
+        assert_eq!(
+            cache.try_get_or_insert_with("a", || Err("Failed to load")),
+            Err("Failed to load")
+        );
+        cache.sync();
+        assert_eq!(cache.get(&"a"), None);
 
-        assert_eq!(cache.remove(&"b"), Some(Arc::new("bob")));
+        assert_eq!(
+            cache.try_get_or_insert_with("a", || Ok::<_, &str>("alice")),
+            Ok(Arc::new("alice"))
+        );
+        assert_eq!(
+            cache.try_get_or_insert_with("a", || Err("Failed to load")),
+            Ok(Arc::new("alice"))
+        );
     }
 }
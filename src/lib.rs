@@ -1,9 +1,40 @@
 use std::sync::Arc;
+use std::time::Duration;
 
+#[macro_use]
+mod trace;
+
+mod async_cache;
+mod builder;
 mod cache;
+mod clock;
+mod deques;
+mod expiration;
+mod frequency_sketch;
+mod hill_climber;
 mod lfu;
 mod linked_list;
+mod maintenance;
+#[cfg(feature = "prometheus")]
+mod metrics;
 mod naive_lfu;
+mod notification;
+mod read_buffer;
+mod stats;
+mod timer_wheel;
+mod value_initializer;
+
+pub use async_cache::AsyncLFUCache;
+pub use builder::{CacheBuilder, LFUCacheBuilder, NaiveLFUCacheBuilder};
+pub use cache::Cache;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use lfu::{Backpressure, LFUCache};
+pub use maintenance::Maintainer;
+#[cfg(feature = "prometheus")]
+pub use metrics::CacheMetrics;
+pub use naive_lfu::NaiveLFUCache;
+pub use notification::RemovalCause;
+pub use stats::CacheStats;
 
 // Interior mutability (no need for `&mut self`)
 pub trait ConcurrentCache<K, V> {
@@ -15,7 +46,18 @@ pub trait ConcurrentCache<K, V> {
     where
         F: FnOnce() -> V;
 
+    /// Like `get_or_insert_with`, but `init` may fail. The error is not
+    /// cached, and concurrent callers for the same key get a clone of it.
+    fn try_get_or_insert_with<F, E>(&self, key: K, init: F) -> Result<Arc<V>, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+        E: Clone + Send + Sync + 'static;
+
     fn insert(&self, key: K, value: V);
 
+    /// Like `insert`, but the entry expires once `ttl` has passed, whatever
+    /// the cache-wide time-to-live is.
+    fn insert_with_ttl(&self, key: K, value: V, ttl: Duration);
+
     fn remove(&self, key: &K) -> Option<Arc<V>>;
 }
@@ -1,5 +1,6 @@
 use std::ptr::NonNull;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CacheRegion {
     Window,
     MainProbation,
@@ -13,7 +14,7 @@ pub struct LinkedList<T> {
     // marker: PhantomData<Box<Node<T>>>,
 }
 
-struct Node<T> {
+pub struct Node<T> {
     elem: T,
     next: Option<NonNull<Node<T>>>,
     prev: Option<NonNull<Node<T>>>,
@@ -27,12 +28,26 @@ impl<T> Node<T> {
             prev: None,
         }
     }
+    #[allow(clippy::boxed_local)]
     fn into_elem(self: Box<Self>) -> T {
         self.elem
     }
+
+    pub fn element(&self) -> &T {
+        &self.elem
+    }
+
+    pub fn element_mut(&mut self) -> &mut T {
+        &mut self.elem
+    }
+
+    pub fn next_node(&self) -> Option<NonNull<Node<T>>> {
+        self.next
+    }
 }
 
 // private methods
+#[allow(dead_code)]
 impl<T> LinkedList<T> {
     #[inline]
     fn push_front_node(&mut self, mut node: Box<Node<T>>) {
@@ -109,6 +124,7 @@ impl<T> LinkedList<T> {
     }
 }
 
+#[allow(dead_code)]
 impl<T> LinkedList<T> {
     pub const fn new() -> Self {
         Self {
@@ -130,6 +146,10 @@ impl<T> LinkedList<T> {
         unsafe { self.head.as_ref().map(|head| &head.as_ref().elem) }
     }
 
+    pub fn front_node(&self) -> Option<NonNull<Node<T>>> {
+        self.head
+    }
+
     pub fn pop_front(&mut self) -> Option<T> {
         self.pop_front_node().map(Node::into_elem)
     }
@@ -149,9 +169,30 @@ impl<T> LinkedList<T> {
             },
         }
     }
+
+    /// Unlinks the node from this list and returns its element.
+    ///
+    /// # Safety
+    ///
+    /// `node` must be a node of this list. It is deallocated by this call.
+    pub unsafe fn remove(&mut self, node: NonNull<Node<T>>) -> T {
+        self.unlink_node(node);
+        Box::from_raw(node.as_ptr()).into_elem()
+    }
+
+    /// Unlinks the node from this list and appends it to the back of `other`.
+    /// The node keeps its address, so pointers to it stay valid.
+    ///
+    /// # Safety
+    ///
+    /// `node` must be a node of this list.
+    pub unsafe fn move_to_back_of(&mut self, node: NonNull<Node<T>>, other: &mut LinkedList<T>) {
+        self.unlink_node(node);
+        other.push_back_node(Box::from_raw(node.as_ptr()));
+    }
 }
 
-unsafe impl<#[may_dangle] T> Drop for LinkedList<T> {
+impl<T> Drop for LinkedList<T> {
     fn drop(&mut self) {
         struct DropGuard<'a, T>(&'a mut LinkedList<T>);
 
@@ -179,9 +220,28 @@ mod tests {
     fn basic() {
         let mut linkedlist = LinkedList::new();
         let n1 = linkedlist.push_back(3);
-        let n2 = linkedlist.push_back(4);
+        linkedlist.push_back(4);
         linkedlist.move_to_back(n1);
         assert_eq!(linkedlist.pop_front(), Some(4));
         assert_eq!(linkedlist.pop_front(), Some(3));
     }
+
+    #[test]
+    fn remove_and_move_between_lists() {
+        let mut list0 = LinkedList::new();
+        let mut list1 = LinkedList::new();
+        let n1 = list0.push_back(1).unwrap();
+        let n2 = list0.push_back(2).unwrap();
+        let n3 = list0.push_back(3).unwrap();
+
+        unsafe {
+            assert_eq!(list0.remove(n2), 2);
+            list0.move_to_back_of(n1, &mut list1);
+            assert_eq!(n3.as_ref().element(), &3);
+        }
+        assert_eq!(list0.len(), 1);
+        assert_eq!(list1.len(), 1);
+        assert_eq!(list0.front(), Some(&3));
+        assert_eq!(list1.front(), Some(&1));
+    }
 }
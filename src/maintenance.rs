@@ -0,0 +1,206 @@
+use crate::{AsyncLFUCache, LFUCache};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Runs one maintenance pass over a cache. Returns `false` once the cache has
+/// been dropped.
+pub(crate) type MaintenanceTask = Box<dyn Fn() -> bool + Send>;
+
+/// A background thread which maintains `LFUCache`s on a schedule: it applies
+/// their pending reads and writes, evicts, and removes the expired entries,
+/// as `sync` does. Without it, the policy only catches up when a caller
+/// crosses a buffer's high-water mark or calls `sync`, so a cache which is
+/// rarely used may keep expired entries for long.
+///
+/// A maintainer may be shared by any number of caches. It does not keep them
+/// alive: while none of them is, its thread sleeps until another cache is
+/// registered. The thread stops once the maintainer and every cache it
+/// maintains have been dropped.
+///
+/// ```
+/// use cache_rs::{ConcurrentCache, LFUCache, Maintainer};
+/// use std::time::Duration;
+///
+/// let users: LFUCache<u32, String, _> = LFUCache::builder(1_000)
+///     .time_to_live(Duration::from_secs(60))
+///     .build();
+/// let sessions: LFUCache<u64, String, _> = LFUCache::new(1_000);
+///
+/// let maintainer = Maintainer::new(Duration::from_secs(1));
+/// maintainer.maintain(&users);
+/// maintainer.maintain(&sessions);
+/// ```
+pub struct Maintainer {
+    tasks: Sender<MaintenanceTask>,
+}
+
+impl Maintainer {
+    /// Spawns a thread which maintains the caches every `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Self::spawn(interval).0
+    }
+
+    fn spawn(interval: Duration) -> (Self, JoinHandle<()>) {
+        let (snd, rcv) = crossbeam_channel::unbounded();
+        let handle = thread::Builder::new()
+            .name("cache-rs-maintainer".to_string())
+            .spawn(move || run(rcv, interval))
+            .expect("Failed to spawn the maintainer thread");
+        (Self { tasks: snd }, handle)
+    }
+
+    pub fn maintain<K, V, S>(&self, cache: &LFUCache<K, V, S>)
+    where
+        K: Debug + Eq + Hash + Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: BuildHasher + Send + Sync + 'static,
+    {
+        self.register(cache.maintenance_task());
+    }
+
+    pub fn maintain_async<K, V, S>(&self, cache: &AsyncLFUCache<K, V, S>)
+    where
+        K: Debug + Eq + Hash + Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: BuildHasher + Send + Sync + 'static,
+    {
+        self.register(cache.maintenance_task());
+    }
+
+    fn register(&self, task: MaintenanceTask) {
+        // The thread only stops once every sender has been dropped.
+        self.tasks
+            .send(task)
+            .expect("The maintainer thread has stopped");
+    }
+}
+
+fn run(rcv: Receiver<MaintenanceTask>, interval: Duration) {
+    let mut tasks = Vec::new();
+    // Whether the maintainer is still around to register caches.
+    let mut registering = true;
+    let mut deadline = Instant::now() + interval;
+    while registering || !tasks.is_empty() {
+        if registering {
+            // Without a cache to maintain, there is no need to wake up until
+            // one is registered.
+            let received = if tasks.is_empty() {
+                rcv.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                rcv.recv_deadline(deadline)
+            };
+            match received {
+                Ok(task) => {
+                    if tasks.is_empty() {
+                        deadline = Instant::now() + interval;
+                    }
+                    tasks.push(task);
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    registering = false;
+                    continue;
+                }
+            }
+        } else {
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        }
+        tasks.retain(|task: &MaintenanceTask| task());
+        deadline = Instant::now() + interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Maintainer;
+    use crate::{ConcurrentCache, LFUCache, MockClock, RemovalCause};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const INTERVAL: Duration = Duration::from_millis(5);
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "Timed out");
+            thread::sleep(INTERVAL);
+        }
+    }
+
+    #[test]
+    fn maintain() {
+        let clock = MockClock::new();
+        let maintainer = Maintainer::new(INTERVAL);
+        let caches = (0..2)
+            .map(|_| {
+                let cache = LFUCache::builder(10)
+                    .time_to_live(Duration::from_secs(60))
+                    .clock(clock.clone())
+                    .record_stats()
+                    .build();
+                maintainer.maintain(&cache);
+                cache.insert(1, "a");
+                cache
+            })
+            .collect::<Vec<_>>();
+
+        // The entries are removed without the caches being touched.
+        clock.advance(Duration::from_secs(61));
+        for cache in &caches {
+            wait_until(|| cache.stats().removals(RemovalCause::Expired) == 1);
+        }
+    }
+
+    #[test]
+    fn shutdown() {
+        let (maintainer, handle) = Maintainer::spawn(INTERVAL);
+        let cache: LFUCache<u32, u32, _> = LFUCache::new(10);
+        maintainer.maintain(&cache);
+
+        // The thread keeps maintaining the cache without the maintainer.
+        drop(maintainer);
+        thread::sleep(INTERVAL * 4);
+        assert!(!handle.is_finished());
+
+        drop(cache);
+        handle.join().expect("Failed to join");
+    }
+
+    #[test]
+    fn idle() {
+        let (maintainer, handle) = Maintainer::spawn(INTERVAL);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let task_runs = Arc::clone(&runs);
+        // A task whose cache is gone after its first run.
+        maintainer.register(Box::new(move || {
+            task_runs.fetch_add(1, Ordering::Relaxed);
+            false
+        }));
+        wait_until(|| runs.load(Ordering::Relaxed) == 1);
+
+        // The thread sleeps without any cache, until one is registered.
+        thread::sleep(INTERVAL * 4);
+        assert!(!handle.is_finished());
+        let clock = MockClock::new();
+        let cache = LFUCache::builder(10)
+            .time_to_live(Duration::from_secs(60))
+            .clock(clock.clone())
+            .record_stats()
+            .build();
+        maintainer.maintain(&cache);
+        cache.insert(1, "a");
+        clock.advance(Duration::from_secs(61));
+        wait_until(|| cache.stats().removals(RemovalCause::Expired) == 1);
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+
+        drop(maintainer);
+        drop(cache);
+        handle.join().expect("Failed to join");
+    }
+}
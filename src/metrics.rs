@@ -0,0 +1,230 @@
+use crate::lfu::LFUCache;
+use crate::notification::RemovalCause;
+use crate::stats::CacheStats;
+use parking_lot::Mutex;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Opts};
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+
+const EVICTION_CAUSES: [(RemovalCause, &str); 4] = [
+    (RemovalCause::Size, "size"),
+    (RemovalCause::Expired, "expired"),
+    (RemovalCause::Rejected, "rejected"),
+    (RemovalCause::Dropped, "dropped"),
+];
+
+pub(crate) struct MetricsSnapshot {
+    pub(crate) entry_count: u64,
+    pub(crate) weighted_size: u64,
+    pub(crate) window_capacity: u64,
+    pub(crate) stats: CacheStats,
+}
+
+pub(crate) type MetricsSource = Box<dyn Fn() -> Option<MetricsSnapshot> + Send + Sync>;
+
+/// Exports the size and the statistics of an `LFUCache` to Prometheus. Every
+/// metric has a `cache` label with the given name. The counters stay at zero
+/// unless the cache was built with `record_stats`.
+///
+/// It does not keep the cache alive. Once the cache has been dropped, the
+/// last collected values are reported.
+///
+/// ```
+/// use cache_rs::{CacheMetrics, LFUCache};
+/// use prometheus::{Encoder, Registry, TextEncoder};
+///
+/// let cache: LFUCache<u32, String, _> = LFUCache::builder(1_000).record_stats().build();
+/// let registry = Registry::new();
+/// registry
+///     .register(Box::new(CacheMetrics::new(&cache, "users").unwrap()))
+///     .unwrap();
+///
+/// let mut buffer = Vec::new();
+/// TextEncoder::new()
+///     .encode(&registry.gather(), &mut buffer)
+///     .unwrap();
+/// ```
+pub struct CacheMetrics {
+    source: MetricsSource,
+    size: IntGauge,
+    weighted_size: IntGauge,
+    window_capacity: IntGauge,
+    hits: IntCounter,
+    misses: IntCounter,
+    evictions: IntCounterVec,
+    dropped_reads: IntCounter,
+    dropped_writes: IntCounter,
+    // Collecting resets the counters before setting them to the cache's
+    // counts, so concurrent collections must not interleave.
+    collect_lock: Mutex<()>,
+}
+
+impl CacheMetrics {
+    pub fn new<K, V, S>(cache: &LFUCache<K, V, S>, name: &str) -> prometheus::Result<Self>
+    where
+        K: Debug + Eq + Hash + Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: BuildHasher + Send + Sync + 'static,
+    {
+        let opts = |metric: &str, help: &str| Opts::new(metric, help).const_label("cache", name);
+        Ok(Self {
+            source: cache.metrics_source(),
+            size: IntGauge::with_opts(opts("cache_size", "The number of entries."))?,
+            weighted_size: IntGauge::with_opts(opts(
+                "cache_weighted_size",
+                "The total weight of the entries known to the policy.",
+            ))?,
+            window_capacity: IntGauge::with_opts(opts(
+                "cache_window_capacity",
+                "The weight the window holds at most, as adapted to the workload.",
+            ))?,
+            hits: IntCounter::with_opts(opts("cache_hits_total", "Lookups which found a value."))?,
+            misses: IntCounter::with_opts(opts(
+                "cache_misses_total",
+                "Lookups which found no value.",
+            ))?,
+            evictions: IntCounterVec::new(
+                opts(
+                    "cache_evictions_total",
+                    "Entries the cache removed on its own, by cause.",
+                ),
+                &["cause"],
+            )?,
+            dropped_reads: IntCounter::with_opts(opts(
+                "cache_dropped_reads_total",
+                "Reads dropped from the full read buffer.",
+            ))?,
+            dropped_writes: IntCounter::with_opts(opts(
+                "cache_dropped_writes_total",
+                "Writes dropped because the write buffer was full.",
+            ))?,
+            collect_lock: Mutex::new(()),
+        })
+    }
+
+    fn update(&self, snapshot: MetricsSnapshot) {
+        let MetricsSnapshot {
+            entry_count,
+            weighted_size,
+            window_capacity,
+            stats,
+        } = snapshot;
+        self.size.set(entry_count as i64);
+        self.weighted_size.set(weighted_size as i64);
+        self.window_capacity.set(window_capacity as i64);
+        set_counter(&self.hits, stats.hits);
+        set_counter(&self.misses, stats.misses);
+        for (cause, label) in EVICTION_CAUSES.iter() {
+            let counter = self.evictions.with_label_values(&[label]);
+            set_counter(&counter, stats.removals(*cause));
+        }
+        set_counter(&self.dropped_reads, stats.dropped_reads);
+        set_counter(&self.dropped_writes, stats.dropped_writes);
+    }
+}
+
+fn set_counter(counter: &IntCounter, value: u64) {
+    counter.reset();
+    counter.inc_by(value);
+}
+
+impl Collector for CacheMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = Vec::new();
+        descs.extend(self.size.desc());
+        descs.extend(self.weighted_size.desc());
+        descs.extend(self.window_capacity.desc());
+        descs.extend(self.hits.desc());
+        descs.extend(self.misses.desc());
+        descs.extend(self.evictions.desc());
+        descs.extend(self.dropped_reads.desc());
+        descs.extend(self.dropped_writes.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let _lock = self.collect_lock.lock();
+        if let Some(snapshot) = (self.source)() {
+            self.update(snapshot);
+        }
+
+        let mut families = Vec::new();
+        families.extend(self.size.collect());
+        families.extend(self.weighted_size.collect());
+        families.extend(self.window_capacity.collect());
+        families.extend(self.hits.collect());
+        families.extend(self.misses.collect());
+        families.extend(self.evictions.collect());
+        families.extend(self.dropped_reads.collect());
+        families.extend(self.dropped_writes.collect());
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CacheMetrics;
+    use crate::{ConcurrentCache, LFUCache};
+    use prometheus::{Encoder, Registry, TextEncoder};
+
+    fn scrape(registry: &Registry) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .expect("Failed to encode");
+        String::from_utf8(buffer).expect("Invalid UTF-8")
+    }
+
+    #[test]
+    fn text_exposition() {
+        // window: 1, probation + protected: 2
+        let cache = LFUCache::builder(3)
+            .weigher(|_, v: &Vec<u8>| v.len() as u32)
+            .max_weight(30)
+            .record_stats()
+            .build();
+        let registry = Registry::new();
+        registry
+            .register(Box::new(CacheMetrics::new(&cache, "users").unwrap()))
+            .unwrap();
+
+        cache.insert("a", vec![0; 10]);
+        cache.insert("b", vec![0; 10]);
+        cache.sync();
+        cache.get(&"a");
+        cache.get(&"z");
+        // "huge" is heavier than the main space, and rejected.
+        cache.insert("huge", vec![0; 40]);
+        cache.insert("c", vec![0; 5]);
+        cache.sync();
+
+        let text = scrape(&registry);
+        for line in &[
+            "# TYPE cache_size gauge",
+            "cache_size{cache=\"users\"} 3",
+            "cache_weighted_size{cache=\"users\"} 25",
+            "cache_window_capacity{cache=\"users\"} 1",
+            "# TYPE cache_hits_total counter",
+            "cache_hits_total{cache=\"users\"} 1",
+            "cache_misses_total{cache=\"users\"} 1",
+            "cache_evictions_total{cache=\"users\",cause=\"dropped\"} 0",
+            "cache_evictions_total{cache=\"users\",cause=\"expired\"} 0",
+            "cache_evictions_total{cache=\"users\",cause=\"rejected\"} 1",
+            "cache_evictions_total{cache=\"users\",cause=\"size\"} 0",
+            "cache_dropped_reads_total{cache=\"users\"} 0",
+            "cache_dropped_writes_total{cache=\"users\"} 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == *line),
+                "{} not in:\n{}",
+                line,
+                text
+            );
+        }
+
+        // Scraping again reports the same counts.
+        assert_eq!(scrape(&registry), text);
+    }
+}
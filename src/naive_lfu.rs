@@ -1,11 +1,20 @@
+use crate::builder::{NaiveLFUCacheBuilder, Weigher};
+use crate::expiration::{EntryTimes, Expiration, TimeSource};
+use crate::frequency_sketch::admit;
+use crate::notification::RemovalCause;
+use crate::stats::{CacheStats, Counter, StatsCounter};
 use crate::ConcurrentCache;
 use count_min_sketch::CountMinSketch8;
 use parking_lot::lock_api::MutexGuard;
 use parking_lot::{Mutex, RawMutex};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::fmt::Debug;
 use std::hash::Hash;
 use std::sync::Arc;
+use std::time::Duration;
 
 pub struct NaiveLFUCache<K, V> {
     inner: Mutex<NaiveLFUInner<K, V>>,
@@ -16,10 +25,25 @@ where
     K: Debug + Eq + Hash + Clone,
 {
     pub fn new(capacity: usize) -> Self {
+        NaiveLFUCacheBuilder::new(capacity).build()
+    }
+
+    pub fn builder(capacity: usize) -> NaiveLFUCacheBuilder<K, V> {
+        NaiveLFUCacheBuilder::new(capacity)
+    }
+
+    pub(crate) fn from_builder(builder: NaiveLFUCacheBuilder<K, V>) -> Self {
         Self {
-            inner: Mutex::new(NaiveLFUInner::new(capacity)),
+            inner: Mutex::new(NaiveLFUInner::new(builder)),
         }
     }
+
+    /// A snapshot of the statistics. They are only recorded if the cache was
+    /// built with `record_stats`.
+    pub fn stats(&self) -> CacheStats {
+        self.inner_mut().stats.snapshot()
+    }
+
     fn inner_mut(&self) -> MutexGuard<'_, RawMutex, NaiveLFUInner<K, V>> {
         self.inner.lock()
     }
@@ -44,112 +68,301 @@ where
         self.inner_mut().get_or_insert_with(key, default)
     }
 
+    fn try_get_or_insert_with<F, E>(&self, key: K, init: F) -> Result<Arc<V>, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+        E: Clone + Send + Sync + 'static,
+    {
+        self.inner_mut().try_get_or_insert_with(key, init)
+    }
+
     fn insert(&self, key: K, value: V) {
-        self.inner_mut().insert(key, value)
+        self.inner_mut().insert(key, value, None)
+    }
+
+    fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        self.inner_mut().insert(key, value, Some(ttl))
     }
 
     fn remove(&self, key: &K) -> Option<Arc<V>> {
-        self.inner_mut().remove(key)
+        self.inner_mut().remove(key, RemovalCause::Explicit)
     }
 }
 
+// The number of keys compared to find an eviction victim. Sampling a few
+// keys rather than scanning all of them keeps eviction O(1), at the cost of
+// sometimes evicting a key which is not the least frequently used one.
+// Ristretto uses the same sample size.
+const EVICTION_SAMPLE_SIZE: usize = 5;
+
+struct CacheEntry<V> {
+    value: Arc<V>,
+    times: EntryTimes,
+    weight: u64,
+    // The position of the key in `NaiveLFUInner::keys`.
+    index: usize,
+}
+
 struct NaiveLFUInner<K, V> {
-    capacity: usize,
-    cache: HashMap<K, Arc<V>>,
+    max_weight: u64,
+    total_weight: u64,
+    weigher: Option<Weigher<K, V>>,
+    cache: HashMap<K, CacheEntry<V>>,
+    // All cached keys, kept in a vector so that they can be sampled uniformly.
+    keys: Vec<K>,
     frequency_sketch: CountMinSketch8<K>,
+    rng: SmallRng,
+    expiration: Expiration,
+    time_source: TimeSource,
+    stats: StatsCounter,
 }
 
 impl<K, V> NaiveLFUInner<K, V>
 where
     K: Debug + Hash + Eq + Clone,
 {
-    fn new(capacity: usize) -> Self {
+    fn new(builder: NaiveLFUCacheBuilder<K, V>) -> Self {
+        let capacity = builder.capacity;
         let cms_capacity = usize::max(capacity, 100);
         Self {
-            capacity,
+            max_weight: builder.max_weight.unwrap_or(capacity as u64),
+            total_weight: 0,
+            weigher: builder.weigher,
             cache: HashMap::with_capacity(capacity),
+            keys: Vec::with_capacity(capacity),
             frequency_sketch: CountMinSketch8::new(cms_capacity, 0.95, 10.0).expect("CMS"),
+            rng: SmallRng::from_entropy(),
+            expiration: builder.expiration,
+            time_source: TimeSource::new(builder.clock),
+            stats: StatsCounter::new(builder.record_stats),
         }
     }
     fn get(&mut self, key: &K) -> Option<Arc<V>> {
         self.frequency_sketch.increment(key);
-        println!(
-            "get()    - estimated frequency of {:?}: {}",
-            key,
-            self.frequency_sketch.estimate(&key)
+        trace_event!(
+            key = ?key,
+            frequency = self.frequency_sketch.estimate(key),
+            "get"
         );
-        self.cache.get(key).map(|v| Arc::clone(v))
+        let now = self.time_source.now();
+        let entry = match self.cache.get(key) {
+            Some(entry) => entry,
+            None => {
+                self.stats.record(Counter::Miss);
+                return None;
+            }
+        };
+        if self.expiration.is_expired(&entry.times, now) {
+            self.stats.record(Counter::Miss);
+            self.remove(key, RemovalCause::Expired);
+            return None;
+        }
+        self.stats.record(Counter::Hit);
+        entry.times.set_last_accessed(now);
+        Some(Arc::clone(&entry.value))
     }
 
     fn get_or_insert_with<F>(&mut self, key: K, default: F) -> Arc<V>
     where
         F: FnOnce() -> V,
+    {
+        match self.try_get_or_insert_with(key, || Ok::<_, Infallible>(default())) {
+            Ok(v) => v,
+            Err(e) => match e {},
+        }
+    }
+
+    fn try_get_or_insert_with<F, E>(&mut self, key: K, init: F) -> Result<Arc<V>, E>
+    where
+        F: FnOnce() -> Result<V, E>,
     {
         self.frequency_sketch.increment(&key);
 
         // NOTE: We cannot use `Entry::or_insert_with()` here because we must
         // check if the key has enough reputation for admission.
         if let Some(v) = self.get(&key) {
-            v
+            Ok(v)
         } else {
-            let v = Arc::new(default());
-            self.do_insert(key, Arc::clone(&v));
-            v
+            let v = Arc::new(init()?);
+            self.do_insert(key, Arc::clone(&v), None);
+            Ok(v)
         }
     }
 
-    fn insert(&mut self, key: K, value: V) {
-        println!(
-            "insert() - estimated frequency of {:?}: {}",
-            key,
-            self.frequency_sketch.estimate(&key)
+    fn insert(&mut self, key: K, value: V, ttl: Option<Duration>) {
+        trace_event!(
+            key = ?key,
+            frequency = self.frequency_sketch.estimate(&key),
+            "insert"
         );
-        self.do_insert(key, Arc::new(value));
+        self.do_insert(key, Arc::new(value), ttl);
+    }
+
+    fn remove(&mut self, key: &K, cause: RemovalCause) -> Option<Arc<V>> {
+        let CacheEntry {
+            value,
+            index,
+            weight,
+            ..
+        } = self.cache.remove(key)?;
+        self.stats.record_removal(cause);
+        trace_event!(key = ?key, ?cause, "removed an entry");
+        self.total_weight -= weight;
+        self.keys.swap_remove(index);
+        // Fix the index of the key moved into the removed key's slot.
+        if let Some(moved) = self.keys.get(index) {
+            if let Some(entry) = self.cache.get_mut(moved) {
+                entry.index = index;
+            }
+        }
+        Some(value)
     }
 
-    fn remove(&mut self, key: &K) -> Option<Arc<V>> {
-        self.cache.remove(key)
+    fn admit(&self, candidate: &K, victims_frequency: u64) -> bool {
+        let candidate_frequency = u64::from(self.frequency_sketch.estimate(candidate));
+        admit(candidate_frequency, victims_frequency)
     }
 
-    fn admit(&self, candidate: &K, victim: &K) -> bool {
-        let freq = &self.frequency_sketch;
-        // TODO: Implement some randomness to mitigate hash DoS.
-        freq.estimate(candidate) > freq.estimate(victim)
+    fn is_expired(&self, key: &K) -> bool {
+        let now = self.time_source.now();
+        self.expiration.is_expired(&self.cache[key].times, now)
+    }
+
+    fn weigh(&self, key: &K, value: &V) -> u64 {
+        self.weigher
+            .as_ref()
+            .map_or(1, |weigher| u64::from(weigher(key, value)))
+    }
+
+    fn do_insert(&mut self, key: K, value: Arc<V>, ttl: Option<Duration>) {
+        let times = EntryTimes::new(self.time_source.now(), ttl);
+        let weight = self.weigh(&key, &value);
+        if let Some(entry) = self.cache.get_mut(&key) {
+            self.stats.record(Counter::Update);
+            self.stats.record_removal(RemovalCause::Replaced);
+            self.total_weight = self.total_weight - entry.weight + weight;
+            entry.value = value;
+            entry.times = times;
+            entry.weight = weight;
+            // The new value may be heavier than the old one.
+            while self.total_weight > self.max_weight {
+                match self.find_cache_victim(self.keys.len()) {
+                    Some((index, expired)) => {
+                        let victim = self.keys[index].clone();
+                        self.evict(&victim, expired);
+                    }
+                    None => break,
+                };
+            }
+        } else if let Some(victims) = self.find_victims(&key, weight) {
+            for victim in victims {
+                let expired = self.is_expired(&victim);
+                self.evict(&victim, expired);
+            }
+            self.stats.record(Counter::Insert);
+            self.push(key, value, times, weight);
+        } else {
+            self.stats.record(Counter::Rejection);
+        }
     }
 
-    fn do_insert(&mut self, key: K, value: Arc<V>) {
-        if self.cache.len() < self.capacity {
-            self.cache.insert(key, value);
+    fn evict(&mut self, key: &K, expired: bool) {
+        let cause = if expired {
+            RemovalCause::Expired
         } else {
-            let victim = self.find_cache_victim();
-            if self.admit(&key, &victim) {
-                self.cache.remove(&victim);
-                self.cache.insert(key, value);
+            RemovalCause::Size
+        };
+        self.remove(key, cause);
+    }
+
+    /// Returns the keys to evict to make room for the candidate, or `None` if
+    /// it is rejected. The candidate is admitted if its estimated frequency
+    /// is higher than the combined frequency of the victims. Expired victims
+    /// are evicted without competing for admission.
+    ///
+    /// Each victim is moved to the back of `keys`, past the keys which are
+    /// sampled for the next one, so that finding a victim takes constant
+    /// time however many have been found.
+    fn find_victims(&mut self, candidate: &K, weight: u64) -> Option<Vec<K>> {
+        if weight > self.max_weight {
+            return None;
+        }
+
+        let mut excess = (self.total_weight + weight).saturating_sub(self.max_weight);
+        let mut victims = Vec::new();
+        let mut victims_frequency = 0;
+        let mut all_expired = true;
+        let mut sampled = self.keys.len();
+        while excess > 0 {
+            let (index, expired) = self.find_cache_victim(sampled)?;
+            sampled -= 1;
+            self.swap_keys(index, sampled);
+            let victim = self.keys[sampled].clone();
+            excess = excess.saturating_sub(self.cache[&victim].weight);
+            if !expired {
+                victims_frequency += u64::from(self.frequency_sketch.estimate(&victim));
+                all_expired = false;
             }
+            victims.push(victim);
+        }
+
+        if all_expired || self.admit(candidate, victims_frequency) {
+            Some(victims)
+        } else {
+            None
+        }
+    }
+
+    fn push(&mut self, key: K, value: Arc<V>, times: EntryTimes, weight: u64) {
+        let index = self.keys.len();
+        self.keys.push(key.clone());
+        self.total_weight += weight;
+        self.cache.insert(
+            key,
+            CacheEntry {
+                value,
+                times,
+                weight,
+                index,
+            },
+        );
+    }
+
+    /// Samples up to `EVICTION_SAMPLE_SIZE` distinct keys among the first
+    /// `sampled` ones of `keys`, and returns the index of an expired one if
+    /// any, or else of the least frequently used one. The flag tells whether
+    /// the key has expired.
+    fn find_cache_victim(&mut self, sampled: usize) -> Option<(usize, bool)> {
+        if sampled == 0 {
+            return None;
         }
+        let sample_size = usize::min(sampled, EVICTION_SAMPLE_SIZE);
+        let now = self.time_source.now();
+        let (cache, freq, expiration) = (&self.cache, &self.frequency_sketch, &self.expiration);
+        let keys = &self.keys;
+        rand::seq::index::sample(&mut self.rng, sampled, sample_size)
+            .into_iter()
+            .map(|i| (i, expiration.is_expired(&cache[&keys[i]].times, now)))
+            .min_by_key(|(i, expired)| (!*expired, freq.estimate(&keys[*i])))
     }
 
-    fn find_cache_victim(&self) -> K {
-        let mut victim = None;
-        for key in self.cache.keys() {
-            let freq0 = self.frequency_sketch.estimate(key);
-            match victim {
-                None => victim = Some((freq0, key)),
-                Some((freq1, _)) if freq0 < freq1 => victim = Some((freq0, key)),
-                Some(_) => (),
+    fn swap_keys(&mut self, a: usize, b: usize) {
+        self.keys.swap(a, b);
+        for &index in &[a, b] {
+            if let Some(entry) = self.cache.get_mut(&self.keys[index]) {
+                entry.index = index;
             }
         }
-        // TODO: Remove clone().
-        // Maybe the cache map should have <Arc<K>, Arc<V>> instead of <K, Arc<V>>?
-        victim.expect("No victim found").1.clone()
     }
 }
 
-// To see the debug prints, run test as `cargo test -- --nocapture`
 #[cfg(test)]
 mod tests {
-    use super::{ConcurrentCache, NaiveLFUCache};
+    use super::{ConcurrentCache, NaiveLFUCache, NaiveLFUCacheBuilder, NaiveLFUInner};
+    use crate::clock::MockClock;
+    use crate::notification::RemovalCause;
     use std::sync::Arc;
+    use std::time::Duration;
 
     #[test]
     fn naive_basics() {
@@ -183,4 +396,118 @@ mod tests {
 
         assert_eq!(cache.remove(&"b"), Some(Arc::new("bob")));
     }
+
+    #[test]
+    fn expiration() {
+        let clock = MockClock::new();
+        let cache = NaiveLFUCache::builder(3)
+            .time_to_live(Duration::from_secs(10))
+            .clock(clock.clone())
+            .build();
+        cache.insert("a", "alice");
+        cache.insert_with_ttl("b", "bob", Duration::from_secs(60));
+        cache.insert("c", "cindy");
+        clock.advance(Duration::from_secs(10));
+
+        // The cache is full, but "d" takes the slot of an expired entry
+        // without competing for admission.
+        cache.insert("d", "david");
+        assert_eq!(cache.get(&"d"), Some(Arc::new("david")));
+        assert_eq!(cache.get(&"b"), Some(Arc::new("bob")));
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"c"), None);
+    }
+
+    #[test]
+    fn stats() {
+        let cache = NaiveLFUCache::builder(3).record_stats().build();
+        cache.insert("a", "alice");
+        cache.insert("b", "bob");
+        cache.get(&"a");
+        cache.get(&"z");
+        cache.insert("a", "anna");
+        cache.insert("c", "cindy");
+        // "d" is rejected because its frequency is too low.
+        cache.insert("d", "david");
+        cache.remove(&"b");
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(stats.hit_ratio(), 0.5);
+        assert_eq!((stats.inserts, stats.updates), (3, 1));
+        assert_eq!(stats.rejections, 1);
+        assert_eq!(stats.removals(RemovalCause::Replaced), 1);
+        assert_eq!(stats.removals(RemovalCause::Explicit), 1);
+        assert_eq!(stats.evictions(), 0);
+    }
+
+    #[test]
+    fn weighted_capacity() {
+        let cache = NaiveLFUCache::builder(10)
+            .max_weight(10)
+            .weigher(|_, v: &Vec<u8>| v.len() as u32)
+            .build();
+        cache.insert("a", vec![0; 4]);
+        cache.insert("b", vec![0; 4]);
+        for _ in 0..3 {
+            cache.get(&"a");
+            cache.get(&"b");
+        }
+
+        cache.insert("huge", vec![0; 11]);
+        assert_eq!(cache.get(&"huge"), None);
+
+        // "c" needs the room of both "a" and "b", and is rejected until its
+        // frequency is higher than theirs combined.
+        cache.insert("c", vec![0; 8]);
+        assert_eq!(cache.get(&"c"), None);
+        for _ in 0..20 {
+            cache.get(&"c");
+        }
+        cache.insert("c", vec![0; 8]);
+        assert_eq!(cache.get(&"c"), Some(Arc::new(vec![0; 8])));
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.inner.lock().total_weight, 8);
+    }
+
+    #[test]
+    fn sampled_eviction() {
+        let mut inner = NaiveLFUInner::new(NaiveLFUCacheBuilder::new(100));
+        for i in 0..1000 {
+            inner.insert(i, i, None);
+            inner.get(&i);
+            if i % 3 == 0 {
+                inner.remove(&(i / 2), RemovalCause::Explicit);
+            }
+        }
+
+        assert!(inner.cache.len() <= 100);
+        assert_eq!(inner.total_weight, inner.cache.len() as u64);
+        assert_eq!(inner.keys.len(), inner.cache.len());
+        for (index, key) in inner.keys.iter().enumerate() {
+            assert_eq!(inner.cache[key].index, index);
+        }
+    }
+
+    #[test]
+    fn many_victims() {
+        let mut inner =
+            NaiveLFUInner::new(NaiveLFUCacheBuilder::new(1_000).weigher(|_, v: &usize| *v as u32));
+        for i in 0..1_000 {
+            inner.insert(i, 1, None);
+        }
+        for _ in 0..10 {
+            inner.get(&1_000);
+        }
+
+        // The heavy entry evicts 500 distinct victims.
+        inner.insert(1_000, 500, None);
+        assert!(inner.cache.contains_key(&1_000));
+        assert_eq!(inner.cache.len(), 501);
+        assert_eq!(inner.total_weight, 1_000);
+        for (index, key) in inner.keys.iter().enumerate() {
+            assert_eq!(inner.cache[key].index, index);
+        }
+    }
 }
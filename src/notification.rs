@@ -0,0 +1,83 @@
+use crossbeam_channel::Sender;
+use std::sync::Arc;
+use std::thread;
+
+/// Why an entry was removed from the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemovalCause {
+    /// Evicted by the policy to keep the cache within its capacity.
+    Size,
+    /// Its time-to-live or time-to-idle has passed.
+    Expired,
+    /// Removed by `remove`.
+    Explicit,
+    /// Its value was replaced by an insert.
+    Replaced,
+    /// Not admitted to the main space because its estimated frequency was
+    /// too low.
+    Rejected,
+    /// Its insert was given up because the write buffer was full, with
+    /// `Backpressure::Drop` or when an eviction listener wrote to the cache
+    /// while the writes were being applied.
+    Dropped,
+}
+
+impl RemovalCause {
+    /// Whether the cache removed the entry on its own, rather than because
+    /// of a `remove` or an insert.
+    pub fn was_evicted(&self) -> bool {
+        matches!(
+            self,
+            RemovalCause::Size
+                | RemovalCause::Expired
+                | RemovalCause::Rejected
+                | RemovalCause::Dropped
+        )
+    }
+}
+
+pub(crate) type EvictionListener<K, V> = Arc<dyn Fn(Arc<K>, Arc<V>, RemovalCause) + Send + Sync>;
+
+/// Delivers removal notifications to the eviction listener.
+pub(crate) enum Notifier<K, V> {
+    /// Calls the listener on the thread which removes the entry, possibly
+    /// while holding the maintenance lock.
+    Immediate(EvictionListener<K, V>),
+    /// Queues the notifications for a dedicated thread, which calls the
+    /// listener. The thread stops once the cache has been dropped.
+    Queued(Sender<(Arc<K>, Arc<V>, RemovalCause)>),
+}
+
+impl<K, V> Notifier<K, V> {
+    pub(crate) fn immediate(listener: EvictionListener<K, V>) -> Self {
+        Notifier::Immediate(listener)
+    }
+
+    pub(crate) fn queued(listener: EvictionListener<K, V>) -> Self
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+    {
+        let (snd, rcv) = crossbeam_channel::unbounded::<(Arc<K>, Arc<V>, RemovalCause)>();
+        thread::Builder::new()
+            .name("cache-rs-notifier".to_string())
+            .spawn(move || {
+                for (key, value, cause) in rcv {
+                    listener(key, value, cause);
+                }
+            })
+            .expect("Failed to spawn the notifier thread");
+        Notifier::Queued(snd)
+    }
+
+    pub(crate) fn notify(&self, key: Arc<K>, value: Arc<V>, cause: RemovalCause) {
+        match self {
+            Notifier::Immediate(listener) => listener(key, value, cause),
+            // The receiver lives as long as the thread, which only stops
+            // when this sender is dropped.
+            Notifier::Queued(snd) => {
+                let _ = snd.send((key, value, cause));
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// The number of ops a stripe holds. Further ops are dropped until the
+/// buffer is drained.
+pub(crate) const STRIPE_CAPACITY: usize = 64;
+
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Spreads the threads over the stripes of every buffer.
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed);
+}
+
+#[repr(align(64))]
+struct Stripe<T> {
+    ops: Mutex<Vec<T>>,
+}
+
+/// Buffers the reads for the policy. It is striped so that threads reading
+/// at the same time rarely contend, and lossy: an op is dropped rather than
+/// waited on when its stripe is full or being drained.
+pub(crate) struct ReadBuffer<T> {
+    stripes: Box<[Stripe<T>]>,
+}
+
+impl<T> ReadBuffer<T> {
+    pub(crate) fn new() -> Self {
+        let parallelism = thread::available_parallelism().map_or(1, |n| n.get());
+        let stripes = (0..(parallelism * 4).next_power_of_two())
+            .map(|_| Stripe {
+                ops: Mutex::new(Vec::new()),
+            })
+            .collect();
+        Self { stripes }
+    }
+
+    /// Adds the op to the current thread's stripe, and returns the number of
+    /// ops in the stripe. Returns `None` if the op was dropped.
+    pub(crate) fn offer(&self, op: T) -> Option<usize> {
+        let index = STRIPE.with(|stripe| *stripe) & (self.stripes.len() - 1);
+        let mut ops = self.stripes[index].ops.try_lock()?;
+        if ops.len() == STRIPE_CAPACITY {
+            return None;
+        }
+        ops.push(op);
+        Some(ops.len())
+    }
+
+    /// Passes the ops of every stripe to `f`, one stripe at a time. Returns
+    /// the number of ops.
+    pub(crate) fn drain(&self, mut f: impl FnMut(T)) -> usize {
+        let mut count = 0;
+        for stripe in self.stripes.iter() {
+            let mut ops = stripe.ops.lock();
+            count += ops.len();
+            ops.drain(..).for_each(&mut f);
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReadBuffer, STRIPE_CAPACITY};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn offer_and_drain() {
+        let buffer = Arc::new(ReadBuffer::new());
+        for i in 0..STRIPE_CAPACITY {
+            assert_eq!(buffer.offer(i), Some(i + 1));
+        }
+        // The stripe is full.
+        assert_eq!(buffer.offer(STRIPE_CAPACITY), None);
+
+        // Other threads are likely to get their own stripes.
+        let handles = (0..4)
+            .map(|_| {
+                let buffer = Arc::clone(&buffer);
+                thread::spawn(move || (0..10).filter_map(|i| buffer.offer(i)).count())
+            })
+            .collect::<Vec<_>>();
+        let offered = handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Failed to join"))
+            .sum::<usize>();
+
+        let mut drained = Vec::new();
+        let count = buffer.drain(|op| drained.push(op));
+        assert_eq!(count, STRIPE_CAPACITY + offered);
+        assert_eq!(drained.len(), count);
+        assert_eq!(buffer.drain(|_| {}), 0);
+        assert_eq!(buffer.offer(0), Some(1));
+    }
+}
@@ -0,0 +1,192 @@
+use crate::notification::RemovalCause;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+const CAUSES: [RemovalCause; 6] = [
+    RemovalCause::Size,
+    RemovalCause::Expired,
+    RemovalCause::Explicit,
+    RemovalCause::Replaced,
+    RemovalCause::Rejected,
+    RemovalCause::Dropped,
+];
+
+// The counters are striped so that threads recording at the same time do not
+// contend on the same cache lines.
+const NUM_STRIPES: usize = 16;
+
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed) % NUM_STRIPES;
+}
+
+/// A snapshot of a cache's statistics. Every count is zero unless the cache
+/// was built with `record_stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups which found a value.
+    pub hits: u64,
+    /// Lookups which found no value.
+    pub misses: u64,
+    /// Values inserted for keys which had none.
+    pub inserts: u64,
+    /// Values inserted in place of existing ones.
+    pub updates: u64,
+    /// Candidates which were not admitted because their estimated frequency
+    /// was too low.
+    pub rejections: u64,
+    /// Reads which were not recorded by the policy because the read buffer
+    /// was full.
+    pub dropped_reads: u64,
+    /// Inserts which were given up because the write buffer was full. See
+    /// `RemovalCause::Dropped`.
+    pub dropped_writes: u64,
+    removals: [u64; CAUSES.len()],
+}
+
+impl CacheStats {
+    pub fn requests(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    /// The ratio of the lookups which found a value, or 1.0 if there has been
+    /// none.
+    pub fn hit_ratio(&self) -> f64 {
+        match self.requests() {
+            0 => 1.0,
+            requests => self.hits as f64 / requests as f64,
+        }
+    }
+
+    /// The number of entries removed for `cause`.
+    pub fn removals(&self, cause: RemovalCause) -> u64 {
+        self.removals[cause as usize]
+    }
+
+    /// The number of entries the cache removed on its own.
+    pub fn evictions(&self) -> u64 {
+        CAUSES
+            .iter()
+            .filter(|cause| cause.was_evicted())
+            .map(|cause| self.removals(*cause))
+            .sum()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Counter {
+    Hit,
+    Miss,
+    Insert,
+    Update,
+    Rejection,
+    DroppedRead,
+    DroppedWrite,
+}
+
+const REMOVALS_OFFSET: usize = Counter::DroppedWrite as usize + 1;
+const NUM_COUNTERS: usize = REMOVALS_OFFSET + CAUSES.len();
+
+#[repr(align(64))]
+#[derive(Default)]
+struct Stripe {
+    counts: [AtomicU64; NUM_COUNTERS],
+}
+
+/// The counters behind `CacheStats`. Recording does nothing unless stats are
+/// enabled.
+pub(crate) struct StatsCounter {
+    stripes: Option<Box<[Stripe]>>,
+}
+
+impl StatsCounter {
+    pub(crate) fn new(enabled: bool) -> Self {
+        let stripes = if enabled {
+            Some((0..NUM_STRIPES).map(|_| Stripe::default()).collect())
+        } else {
+            None
+        };
+        Self { stripes }
+    }
+
+    pub(crate) fn record(&self, counter: Counter) {
+        self.increment(counter as usize);
+    }
+
+    pub(crate) fn record_removal(&self, cause: RemovalCause) {
+        self.increment(REMOVALS_OFFSET + cause as usize);
+    }
+
+    fn increment(&self, index: usize) {
+        if let Some(stripes) = &self.stripes {
+            let stripe = STRIPE.with(|stripe| *stripe);
+            stripes[stripe].counts[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> CacheStats {
+        let stripes = match &self.stripes {
+            Some(stripes) => stripes,
+            None => return CacheStats::default(),
+        };
+        let mut counts = [0; NUM_COUNTERS];
+        for stripe in stripes.iter() {
+            for (count, stripe_count) in counts.iter_mut().zip(stripe.counts.iter()) {
+                *count += stripe_count.load(Ordering::Relaxed);
+            }
+        }
+
+        let mut removals = [0; CAUSES.len()];
+        removals.copy_from_slice(&counts[REMOVALS_OFFSET..]);
+        CacheStats {
+            hits: counts[Counter::Hit as usize],
+            misses: counts[Counter::Miss as usize],
+            inserts: counts[Counter::Insert as usize],
+            updates: counts[Counter::Update as usize],
+            rejections: counts[Counter::Rejection as usize],
+            dropped_reads: counts[Counter::DroppedRead as usize],
+            dropped_writes: counts[Counter::DroppedWrite as usize],
+            removals,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Counter, StatsCounter};
+    use crate::notification::RemovalCause;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn striped_counts() {
+        let counter = Arc::new(StatsCounter::new(true));
+        let handles = (0..4)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        counter.record(Counter::Hit);
+                    }
+                    counter.record(Counter::Miss);
+                    counter.record_removal(RemovalCause::Expired);
+                    counter.record_removal(RemovalCause::Explicit);
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().expect("Failed to join");
+        }
+
+        let stats = counter.snapshot();
+        assert_eq!(stats.hits, 4000);
+        assert_eq!(stats.misses, 4);
+        assert_eq!(stats.hit_ratio(), 4000.0 / 4004.0);
+        assert_eq!(stats.removals(RemovalCause::Explicit), 4);
+        assert_eq!(stats.evictions(), 4);
+
+        let disabled = StatsCounter::new(false);
+        disabled.record(Counter::Hit);
+        assert_eq!(disabled.snapshot().hits, 0);
+    }
+}
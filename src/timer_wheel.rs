@@ -0,0 +1,271 @@
+use crate::linked_list::{LinkedList, Node};
+use std::ptr::NonNull;
+
+// The number of buckets of each level, and the time span of a bucket in
+// nanoseconds as a power of two. Like Caffeine's, the spans are about 1.07s,
+// 1.14m, 1.22h and 0.8d, and the last level is a single overflow bucket.
+const BUCKET_COUNTS: [usize; 5] = [64, 64, 32, 4, 1];
+const SPAN_SHIFTS: [u32; 5] = [30, 36, 42, 46, 48];
+
+// The level of the timers which have fired but are yet to be processed.
+const DUE_LEVEL: usize = BUCKET_COUNTS.len();
+
+pub(crate) struct TimerNode<T> {
+    pub(crate) item: T,
+    time: u64,
+    level: usize,
+    index: usize,
+}
+
+pub(crate) type TimerNodePtr<T> = NonNull<Node<TimerNode<T>>>;
+
+/// A hierarchical timing wheel. Scheduling and cancelling a timer take
+/// constant time, and advancing the wheel only visits the buckets whose time
+/// span has passed, so finding the expired entries is amortized O(1).
+///
+/// The timers of a bucket are cascaded to a lower level when the bucket's
+/// span passes, until they are due. A timer fires up to one level 0 span
+/// late.
+pub(crate) struct TimerWheel<T> {
+    levels: Vec<Vec<LinkedList<TimerNode<T>>>>,
+    due: LinkedList<TimerNode<T>>,
+    current_time: u64,
+}
+
+impl<T> TimerWheel<T> {
+    pub(crate) fn new(now: u64) -> Self {
+        let levels = BUCKET_COUNTS
+            .iter()
+            .map(|&count| (0..count).map(|_| LinkedList::new()).collect())
+            .collect();
+        Self {
+            levels,
+            due: LinkedList::new(),
+            current_time: now,
+        }
+    }
+
+    pub(crate) fn schedule(&mut self, item: T, time: u64) -> TimerNodePtr<T> {
+        let (level, index) = self.bucket_for(time);
+        let node = TimerNode {
+            item,
+            time,
+            level,
+            index,
+        };
+        self.levels[level][index]
+            .push_back(node)
+            .expect("No node was pushed")
+    }
+
+    /// Moves the timer to fire at `time`.
+    ///
+    /// # Safety
+    ///
+    /// `node` must be a timer of this wheel.
+    pub(crate) unsafe fn reschedule(&mut self, mut node: TimerNodePtr<T>, time: u64) {
+        node.as_mut().element_mut().time = time;
+        let (level, index) = self.bucket_for(time);
+        self.move_to(node, level, index);
+    }
+
+    /// Cancels the timer and returns its item.
+    ///
+    /// # Safety
+    ///
+    /// `node` must be a timer of this wheel. It is deallocated by this call.
+    pub(crate) unsafe fn unlink(&mut self, node: TimerNodePtr<T>) -> T {
+        let TimerNode { level, index, .. } = *node.as_ref().element();
+        self.list_mut(level, index).remove(node).item
+    }
+
+    /// Advances the wheel to `now`. The timers which are due by then are
+    /// queued, and `due_front` returns them one by one. Each of them must be
+    /// unlinked or rescheduled by the caller.
+    pub(crate) fn advance(&mut self, now: u64) {
+        let previous_time = self.current_time;
+        if now <= previous_time {
+            return;
+        }
+        self.current_time = now;
+
+        for (level, shift) in SPAN_SHIFTS.iter().enumerate() {
+            let previous_ticks = previous_time >> shift;
+            let current_ticks = now >> shift;
+            if current_ticks == previous_ticks {
+                break;
+            }
+            self.expire_level(level, previous_ticks, current_ticks - previous_ticks);
+        }
+    }
+
+    /// A timer which is due.
+    pub(crate) fn due_front(&self) -> Option<TimerNodePtr<T>> {
+        self.due.front_node()
+    }
+
+    /// Empties the buckets whose span has passed. Their timers are queued if
+    /// they are due, or else moved to the bucket of their time.
+    fn expire_level(&mut self, level: usize, previous_ticks: u64, delta: u64) {
+        let count = BUCKET_COUNTS[level];
+        let mask = count as u64 - 1;
+        let steps = usize::min(delta as usize + 1, count);
+        for step in 0..steps {
+            let index = ((previous_ticks + step as u64) & mask) as usize;
+            // Take the bucket so that the timers moved back into it are not
+            // visited again.
+            let mut bucket = std::mem::replace(&mut self.levels[level][index], LinkedList::new());
+            while let Some(mut node) = bucket.front_node() {
+                let time = unsafe { node.as_ref().element().time };
+                let (level, index) = if time <= self.current_time {
+                    (DUE_LEVEL, 0)
+                } else {
+                    self.bucket_for(time)
+                };
+                unsafe {
+                    let element = node.as_mut().element_mut();
+                    element.level = level;
+                    element.index = index;
+                    bucket.move_to_back_of(node, self.list_mut(level, index));
+                }
+            }
+        }
+    }
+
+    /// The level and the bucket of a timer which fires at `time`.
+    fn bucket_for(&self, time: u64) -> (usize, usize) {
+        let duration = time.saturating_sub(self.current_time);
+        let last = BUCKET_COUNTS.len() - 1;
+        for level in 0..last {
+            if duration < 1 << SPAN_SHIFTS[level + 1] {
+                let index = (time >> SPAN_SHIFTS[level]) as usize & (BUCKET_COUNTS[level] - 1);
+                return (level, index);
+            }
+        }
+        (last, 0)
+    }
+
+    unsafe fn move_to(&mut self, mut node: TimerNodePtr<T>, level: usize, index: usize) {
+        let element = node.as_mut().element_mut();
+        let (old_level, old_index) = (element.level, element.index);
+        element.level = level;
+        element.index = index;
+        if (old_level, old_index) == (level, index) {
+            self.list_mut(level, index).move_to_back(Some(node));
+        } else {
+            let (from, to) = self.two_lists_mut((old_level, old_index), (level, index));
+            from.move_to_back_of(node, to);
+        }
+    }
+
+    fn list_mut(&mut self, level: usize, index: usize) -> &mut LinkedList<TimerNode<T>> {
+        if level == DUE_LEVEL {
+            &mut self.due
+        } else {
+            &mut self.levels[level][index]
+        }
+    }
+
+    fn two_lists_mut(
+        &mut self,
+        a: (usize, usize),
+        b: (usize, usize),
+    ) -> (&mut LinkedList<TimerNode<T>>, &mut LinkedList<TimerNode<T>>) {
+        debug_assert_ne!(a, b);
+        let a = self.list_mut(a.0, a.1) as *mut LinkedList<TimerNode<T>>;
+        let b = self.list_mut(b.0, b.1) as *mut LinkedList<TimerNode<T>>;
+        // The two lists are distinct.
+        unsafe { (&mut *a, &mut *b) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimerWheel;
+
+    const SEC: u64 = 1_000_000_000;
+
+    fn due_keys(wheel: &mut TimerWheel<u32>) -> Vec<u32> {
+        let mut keys = Vec::new();
+        while let Some(node) = wheel.due_front() {
+            keys.push(unsafe { wheel.unlink(node) });
+        }
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn fires_due_timers() {
+        // Drive the wheel with a manually advanced clock.
+        let mut now = 0;
+        let mut wheel = TimerWheel::new(now);
+        wheel.schedule(1, 5 * SEC);
+        wheel.schedule(2, 90 * SEC);
+        wheel.schedule(3, 3 * 3600 * SEC);
+        let cancelled = wheel.schedule(4, 10 * SEC);
+        let rescheduled = wheel.schedule(5, 10 * SEC);
+        wheel.schedule(6, 30 * 24 * 3600 * SEC);
+
+        unsafe {
+            assert_eq!(wheel.unlink(cancelled), 4);
+            wheel.reschedule(rescheduled, 60 * SEC);
+        }
+
+        now += 3 * SEC;
+        wheel.advance(now);
+        assert!(due_keys(&mut wheel).is_empty());
+
+        now += 10 * SEC;
+        wheel.advance(now);
+        assert_eq!(due_keys(&mut wheel), vec![1]);
+
+        now = 62 * SEC;
+        wheel.advance(now);
+        assert_eq!(due_keys(&mut wheel), vec![5]);
+
+        now = 100 * SEC;
+        wheel.advance(now);
+        assert_eq!(due_keys(&mut wheel), vec![2]);
+
+        now = 2 * 3600 * SEC;
+        wheel.advance(now);
+        assert!(due_keys(&mut wheel).is_empty());
+
+        now = 4 * 3600 * SEC;
+        wheel.advance(now);
+        assert_eq!(due_keys(&mut wheel), vec![3]);
+
+        now = 31 * 24 * 3600 * SEC;
+        wheel.advance(now);
+        assert_eq!(due_keys(&mut wheel), vec![6]);
+    }
+
+    #[test]
+    fn variable_ttls() {
+        let mut now = 0;
+        let mut wheel = TimerWheel::new(now);
+        // Times from a few milliseconds to about three days.
+        let times = (0..1000u32)
+            .map(|i| u64::from(i) * u64::from(i) * 250_000_000 + 7_000_000)
+            .collect::<Vec<_>>();
+        for (key, time) in times.iter().enumerate() {
+            wheel.schedule(key as u32, *time);
+        }
+
+        let mut fired = Vec::new();
+        while now < times[999] + 2 * SEC {
+            now += 17 * SEC;
+            wheel.advance(now);
+            for key in due_keys(&mut wheel) {
+                // No timer fires early, or more than one level 0 span late
+                // on top of the clock's step.
+                let time = times[key as usize];
+                assert!(time <= now);
+                assert!(now - time < 17 * SEC + (1 << 30));
+                fired.push(key);
+            }
+        }
+        fired.sort_unstable();
+        assert_eq!(fired, (0..1000).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,46 @@
+// Instrumentation which is compiled in with the `tracing` feature, and
+// compiled out otherwise.
+
+/// Enters a span for a maintenance pass. Subscribers get its duration when
+/// the returned guard is dropped.
+#[cfg(feature = "tracing")]
+macro_rules! maintenance_span {
+    ($($arg:tt)*) => {
+        tracing::debug_span!($($arg)*).entered()
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! maintenance_span {
+    ($($arg:tt)*) => {
+        $crate::trace::NoSpan
+    };
+}
+
+/// Records the value of a field declared empty when the span was entered.
+#[cfg(feature = "tracing")]
+macro_rules! record_span_field {
+    ($span:expr, $field:literal, $value:expr) => {
+        $span.record($field, $value);
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! record_span_field {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($($arg:tt)*) => {
+        tracing::trace!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
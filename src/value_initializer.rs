@@ -0,0 +1,383 @@
+use futures::future::FutureExt;
+use futures::lock::Mutex as AsyncMutex;
+use parking_lot::RwLock;
+use std::any::Any;
+use std::convert::Infallible;
+use std::future::Future;
+use std::hash::Hash;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+enum InitResult<V> {
+    Initialized(Arc<V>),
+    // The error is type-erased because the callers waiting for the same key
+    // may use different error types.
+    Failed(Arc<dyn Any + Send + Sync>),
+    Panicked,
+}
+
+// The thread running the initializer holds the write lock until the result
+// is set, so the other threads block on the read lock.
+type Waiter<V> = Arc<RwLock<Option<InitResult<V>>>>;
+
+/// Coalesces concurrent `get_or_insert_with` calls for the same key, so that
+/// the initializer runs once and every caller gets the same value.
+pub(crate) struct ValueInitializer<K, V> {
+    waiters: cht::HashMap<Arc<K>, Waiter<V>>,
+}
+
+impl<K, V> ValueInitializer<K, V>
+where
+    K: Eq + Hash,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            waiters: cht::HashMap::new(),
+        }
+    }
+
+    /// Runs `init` and stores its value with `insert`, unless another thread
+    /// is already doing so for the key. In that case, waits for that thread
+    /// and returns its value.
+    ///
+    /// `get` is called once this thread has become the initializer, to check
+    /// whether the value was inserted while it was waiting to become one.
+    ///
+    /// If `init` panics, the panic is resumed in this thread and the waiting
+    /// threads panic too.
+    pub(crate) fn init_or_read<G, F, I>(&self, key: &Arc<K>, get: G, init: F, insert: I) -> Arc<V>
+    where
+        G: FnOnce() -> Option<Arc<V>>,
+        F: FnOnce() -> V,
+        I: FnOnce(V) -> Arc<V>,
+    {
+        match self.try_init_or_read(key, get, || Ok::<_, Infallible>(init()), insert) {
+            Ok(v) => v,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Like `init_or_read`, but `init` may fail. The error is not stored, and
+    /// the threads waiting for this thread get a clone of it.
+    pub(crate) fn try_init_or_read<G, F, I, E>(
+        &self,
+        key: &Arc<K>,
+        get: G,
+        init: F,
+        insert: I,
+    ) -> Result<Arc<V>, E>
+    where
+        G: FnOnce() -> Option<Arc<V>>,
+        F: FnOnce() -> Result<V, E>,
+        I: FnOnce(V) -> Arc<V>,
+        E: Clone + Send + Sync + 'static,
+    {
+        let waiter = Arc::new(RwLock::new(None));
+        let mut lock = waiter.write();
+
+        while let Some(existing) = self.try_insert_waiter(key, &waiter) {
+            let result = existing.read();
+            match &*result {
+                Some(InitResult::Initialized(v)) => return Ok(Arc::clone(v)),
+                Some(InitResult::Failed(e)) => {
+                    if let Some(e) = e.downcast_ref::<E>() {
+                        return Err(e.clone());
+                    }
+                    // The other thread's initializer has a different error
+                    // type. Retry, so that this thread runs its own.
+                }
+                Some(InitResult::Panicked) => {
+                    panic!("The initializer panicked in another thread")
+                }
+                // The initializer has been removed without a result, e.g.
+                // because `get` or `insert` panicked. Retry, so that this
+                // thread takes over.
+                None => (),
+            }
+        }
+
+        // This thread is the initializer now. The guard removes the waiter
+        // before the lock is released, even if `get` or `insert` panics.
+        let _guard = RemoveWaiterGuard {
+            waiters: &self.waiters,
+            key,
+            waiter: &waiter,
+        };
+
+        if let Some(v) = get() {
+            *lock = Some(InitResult::Initialized(Arc::clone(&v)));
+            return Ok(v);
+        }
+
+        match panic::catch_unwind(AssertUnwindSafe(init)) {
+            Ok(Ok(value)) => {
+                // Insert the value before removing the waiter, so that a
+                // thread arriving after the removal finds the value.
+                let v = insert(value);
+                *lock = Some(InitResult::Initialized(Arc::clone(&v)));
+                Ok(v)
+            }
+            Ok(Err(e)) => {
+                *lock = Some(InitResult::Failed(Arc::new(e.clone())));
+                Err(e)
+            }
+            Err(payload) => {
+                *lock = Some(InitResult::Panicked);
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    /// Returns the existing waiter for the key, or `None` if `waiter` has been
+    /// inserted.
+    fn try_insert_waiter(&self, key: &Arc<K>, waiter: &Waiter<V>) -> Option<Waiter<V>> {
+        self.waiters
+            .insert_or_modify(Arc::clone(key), Arc::clone(waiter), |_, existing| {
+                Arc::clone(existing)
+            })
+    }
+}
+
+// The task running the initializer holds the lock until the result is set.
+type AsyncWaiter<V> = Arc<AsyncMutex<Option<InitResult<V>>>>;
+
+/// The async counterpart of `ValueInitializer`. Waiting tasks yield to the
+/// executor instead of blocking their thread.
+pub(crate) struct AsyncValueInitializer<K, V> {
+    waiters: cht::HashMap<Arc<K>, AsyncWaiter<V>>,
+}
+
+impl<K, V> AsyncValueInitializer<K, V>
+where
+    K: Eq + Hash,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            waiters: cht::HashMap::new(),
+        }
+    }
+
+    /// See `ValueInitializer::try_init_or_read`.
+    ///
+    /// If the initializing task is dropped before `init` completes, one of
+    /// the waiting tasks runs its own initializer.
+    pub(crate) async fn try_init_or_read<G, F, I, E>(
+        &self,
+        key: &Arc<K>,
+        get: G,
+        init: F,
+        insert: I,
+    ) -> Result<Arc<V>, E>
+    where
+        G: FnOnce() -> Option<Arc<V>>,
+        F: Future<Output = Result<V, E>>,
+        I: FnOnce(V) -> Arc<V>,
+        E: Clone + Send + Sync + 'static,
+    {
+        let waiter = Arc::new(AsyncMutex::new(None));
+        let mut lock = waiter.try_lock().expect("The new waiter is locked");
+
+        while let Some(existing) = self.try_insert_waiter(key, &waiter) {
+            let result = existing.lock().await;
+            match &*result {
+                Some(InitResult::Initialized(v)) => return Ok(Arc::clone(v)),
+                Some(InitResult::Failed(e)) => {
+                    if let Some(e) = e.downcast_ref::<E>() {
+                        return Err(e.clone());
+                    }
+                }
+                Some(InitResult::Panicked) => {
+                    panic!("The initializer panicked in another task")
+                }
+                // The initializing task has been dropped. Retry.
+                None => (),
+            }
+        }
+
+        // This task is the initializer now. The guard removes the waiter
+        // before the lock is released, even if this future is dropped.
+        let _guard = RemoveWaiterGuard {
+            waiters: &self.waiters,
+            key,
+            waiter: &waiter,
+        };
+
+        if let Some(v) = get() {
+            *lock = Some(InitResult::Initialized(Arc::clone(&v)));
+            return Ok(v);
+        }
+
+        match AssertUnwindSafe(init).catch_unwind().await {
+            Ok(Ok(value)) => {
+                let v = insert(value);
+                *lock = Some(InitResult::Initialized(Arc::clone(&v)));
+                Ok(v)
+            }
+            Ok(Err(e)) => {
+                *lock = Some(InitResult::Failed(Arc::new(e.clone())));
+                Err(e)
+            }
+            Err(payload) => {
+                *lock = Some(InitResult::Panicked);
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    fn try_insert_waiter(&self, key: &Arc<K>, waiter: &AsyncWaiter<V>) -> Option<AsyncWaiter<V>> {
+        self.waiters
+            .insert_or_modify(Arc::clone(key), Arc::clone(waiter), |_, existing| {
+                Arc::clone(existing)
+            })
+    }
+}
+
+/// Removes an initializer's waiter, of either kind, when dropped.
+struct RemoveWaiterGuard<'a, K: Eq + Hash, W> {
+    waiters: &'a cht::HashMap<Arc<K>, Arc<W>>,
+    key: &'a Arc<K>,
+    waiter: &'a Arc<W>,
+}
+
+impl<'a, K: Eq + Hash, W> Drop for RemoveWaiterGuard<'a, K, W> {
+    fn drop(&mut self) {
+        let waiter = self.waiter;
+        self.waiters
+            .remove_if(self.key, |_, w| Arc::ptr_eq(w, waiter));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ValueInitializer;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn runs_init_once() {
+        let initializer = Arc::new(ValueInitializer::new());
+        let num_inits = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+        let key = Arc::new("key");
+
+        let handles = (0..8)
+            .map(|i| {
+                let (initializer, num_inits, barrier, key) = (
+                    Arc::clone(&initializer),
+                    Arc::clone(&num_inits),
+                    Arc::clone(&barrier),
+                    Arc::clone(&key),
+                );
+                thread::spawn(move || {
+                    barrier.wait();
+                    let init = || {
+                        num_inits.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(50));
+                        i
+                    };
+                    initializer.init_or_read(&key, || None, init, Arc::new)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let values = handles
+            .into_iter()
+            .map(|h| h.join().expect("Failed to join"))
+            .collect::<Vec<_>>();
+        assert_eq!(num_inits.load(Ordering::SeqCst), 1);
+        assert!(values.iter().all(|v| Arc::ptr_eq(v, &values[0])));
+        assert!(initializer.waiters.is_empty());
+    }
+
+    #[test]
+    fn propagates_panic() {
+        let initializer = Arc::new(ValueInitializer::new());
+        let key = Arc::new("key");
+
+        let leader = {
+            let (initializer, key) = (Arc::clone(&initializer), Arc::clone(&key));
+            thread::spawn(move || {
+                initializer.init_or_read(
+                    &key,
+                    || None,
+                    || -> i32 {
+                        thread::sleep(Duration::from_millis(100));
+                        panic!("Failed to load")
+                    },
+                    Arc::new,
+                )
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        let waiter = {
+            let (initializer, key) = (Arc::clone(&initializer), Arc::clone(&key));
+            thread::spawn(move || initializer.init_or_read(&key, || None, || 1, Arc::new))
+        };
+
+        assert!(leader.join().is_err());
+        assert!(waiter.join().is_err());
+        assert!(initializer.waiters.is_empty());
+
+        // The next call runs its own initializer.
+        assert_eq!(
+            initializer.init_or_read(&key, || None, || 2, Arc::new),
+            Arc::new(2)
+        );
+    }
+
+    #[test]
+    fn insert_panics() {
+        let initializer = ValueInitializer::new();
+        let key = Arc::new("key");
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            initializer.init_or_read(&key, || None, || 1, |_| panic!("Failed to insert"))
+        }));
+        assert!(result.is_err());
+        assert!(initializer.waiters.is_empty());
+
+        // The next call is not left waiting for the failed one.
+        assert_eq!(
+            initializer.init_or_read(&key, || None, || 2, Arc::new),
+            Arc::new(2)
+        );
+    }
+
+    #[test]
+    fn shares_error() {
+        let initializer = Arc::new(ValueInitializer::new());
+        let key = Arc::new("key");
+
+        let leader = {
+            let (initializer, key) = (Arc::clone(&initializer), Arc::clone(&key));
+            thread::spawn(move || {
+                initializer.try_init_or_read(
+                    &key,
+                    || None,
+                    || {
+                        thread::sleep(Duration::from_millis(100));
+                        Err("Failed to load".to_string())
+                    },
+                    Arc::new,
+                )
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        let waiter = {
+            let (initializer, key) = (Arc::clone(&initializer), Arc::clone(&key));
+            thread::spawn(move || initializer.try_init_or_read(&key, || None, || Ok(1), Arc::new))
+        };
+
+        let expected = Err("Failed to load".to_string());
+        assert_eq!(leader.join().expect("Failed to join"), expected);
+        assert_eq!(waiter.join().expect("Failed to join"), expected);
+
+        // The error is not stored.
+        assert_eq!(
+            initializer.try_init_or_read::<_, _, _, String>(&key, || None, || Ok(2), Arc::new),
+            Ok(Arc::new(2))
+        );
+    }
+}